@@ -33,6 +33,15 @@ function M.join_str_array(array)
   return s
 end
 
+-- splits the UTF-8 string into an array of chars
+function M.split_str(s)
+  local array = {}
+  for c in s:gmatch('[%z\1-\127\194-\244][\128-\191]*') do
+    table.insert(array, c)
+  end
+  return array
+end
+
 function M.alert(message, ms)
   ms = ms or 2000
   vim.schedule(function ()
@@ -54,6 +54,7 @@ function M.enable()
     vim.keymap.set("i", "<C-h>", function() M.curr_state.handle_bs() end, {})
     vim.keymap.set("i", "<ESC>", function() M.curr_state.handle_esc() end, {})
     vim.keymap.set("i", "<CR>", function() M.curr_state.handle_cr() end, {})
+    vim.keymap.set("i", "<Tab>", function() M.curr_state.handle_tab() end, {})
 
     M.is_enabled = true
     go_to_direct_input_kana_state()
@@ -71,6 +72,7 @@ local function disable()
     vim.keymap.del("i", "<C-h>")
     vim.keymap.del("i", "<ESC>")
     vim.keymap.del("i", "<CR>")
+    vim.keymap.del("i", "<Tab>")
 
     status.set('-')
     M.is_enabled = false
@@ -98,8 +100,19 @@ end
 function M.apply_settings_override(settings)
   local mo = vim.g.minskk_override
   if mo then
-    if mo.dict_file_path then
-      settings.dict_file_path = mo.dict_file_path
+    if mo.dict_file_paths then
+      settings.dict_file_paths = mo.dict_file_paths
+    elseif mo.dict_file_path then
+      settings.dict_file_paths = { mo.dict_file_path }
+    end
+    if mo.user_dict_file_path then
+      settings.user_dict_file_path = mo.user_dict_file_path
+    end
+    if mo.reverse_index ~= nil then
+      settings.reverse_index = mo.reverse_index
+    end
+    if mo.lazy_load ~= nil then
+      settings.lazy_load = mo.lazy_load
     end
   end
 end
@@ -116,6 +129,12 @@ function M.init()
     end,
   })
 
+  -- not to lose the changes of the user dictionary not saved yet
+  vim.api.nvim_create_autocmd("VimLeavePre", {
+    pattern = "*",
+    callback = select_kanji_state.save_user_dict,
+  })
+
   M.curr_state = direct_input_kana_state
 
   local dfa = {
@@ -127,10 +146,13 @@ function M.init()
   }
   local bs = vim.api.nvim_replace_termcodes("<BS>", true, false, true)
   local cr = vim.api.nvim_replace_termcodes("<CR>", true, false, true)
+  local tab = vim.api.nvim_replace_termcodes("<Tab>", true, false, true)
 
   local util = {
     bs = bs,
     cr = cr,
+    tab = tab,
+    complete = select_kanji_state.complete,
     disable = disable,
     set_dfa_state = set_dfa_state,
     DFAState = DFAState,
@@ -142,12 +164,25 @@ function M.init()
   select_kanji_state.init(dfa, util)
   select_kanji_list_state.init(dfa, util)
 
-  -- load dictionary
+  M.load_dicts()
+end
+
+-- (re)loads the dictionaries in the current settings.
+-- the loaded dictionaries are used until the new ones are ready
+function M.load_dicts()
   local settings = {
-    dict_file_path = '~/.skk/SKK-JISYO.L',
+    dict_file_paths = { '~/.skk/SKK-JISYO.L' },
+    user_dict_file_path = '~/.skk-jisyo',
+    reverse_index = false,
+    lazy_load = false,
   }
   M.apply_settings_override(settings)
-  select_kanji_state.build_dict(settings.dict_file_path)
+  select_kanji_state.build_dict(
+    settings.dict_file_paths,
+    settings.reverse_index,
+    settings.lazy_load
+  )
+  select_kanji_state.load_user_dict(settings.user_dict_file_path)
 end
 
 function _G.minskk_setup(settings)
@@ -159,11 +194,49 @@ function _G.minskk_setup(settings)
 end
 
 function _G.minskk_statusline()
+  local build_status = select_kanji_state.get_build_status()
+  if build_status then
+    return status.get() .. ' ' .. build_status
+  end
   return status.get()
 end
 
+function M.show_warnings()
+  local warnings = select_kanji_state.get_warnings()
+  if #warnings == 0 then
+    vim.notify('MinSKK: no dictionary warnings')
+  else
+    vim.notify('MinSKK: skipped lines\n' .. table.concat(warnings, '\n'), vim.log.levels.WARN)
+  end
+end
+
+-- shows how to type the word. the word under the cursor is used if not given
+function M.show_readings(word)
+  if not word or #word == 0 then
+    word = vim.fn.expand('<cword>')
+  end
+  local readings = {}
+  for _, entry in ipairs(select_kanji_state.reverse_look_up(word)) do
+    -- the okuri kana is shown if known. otherwise the romaji prefix
+    local okuri = entry.okuri:sub(2)
+    if #okuri == 0 then
+      okuri = entry.okuri
+    end
+    table.insert(readings, entry.reading .. okuri)
+  end
+
+  if #readings == 0 then
+    vim.notify('MinSKK: no reading of ' .. word)
+  else
+    vim.notify('MinSKK: ' .. word .. ' ' .. table.concat(readings, ' / '))
+  end
+end
+
 vim.cmd [[
   command! MinSKKEnable lua require 'minskk'.enable()
+  command! MinSKKWarnings lua require 'minskk'.show_warnings()
+  command! MinSKKReload lua require 'minskk'.load_dicts()
+  command! -nargs=? MinSKKReading lua require 'minskk'.show_readings(<q-args>)
 ]]
 
 return M
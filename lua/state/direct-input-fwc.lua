@@ -25,6 +25,10 @@ function M.handle_bs()
   vim.api.nvim_feedkeys(M.util.bs, "in", true)
 end
 
+function M.handle_tab()
+  vim.api.nvim_feedkeys(M.util.tab, "in", true)
+end
+
 function M.handle_esc()
   M.util.disable()
 end
@@ -2,6 +2,7 @@ local M = {
   curr_input_mode = nil,
   reading = {},
   ac_kana_first_char = nil,
+  completion = nil,
 }
 
 local g_kana_tree = require 'state/kana-tree/logic'
@@ -13,6 +14,8 @@ local InputMode = {
   AcKana = 2,
 }
 
+local max_completions = 20
+
 function M.init(dfa, util)
   g_kana_tree.init()
   M.dfa = dfa
@@ -82,7 +85,40 @@ function M.handle_cr()
   M.dfa.go_to_direct_input_kana_state()
 end
 
+function M.handle_tab()
+  if M.curr_input_mode ~= InputMode.Reading
+    or #M.reading == 0
+    or not g_kana_tree.at_the_root_node() then
+    return
+  end
+
+  -- start completing the reading or show the next completion
+  if not M.completion then
+    local readings = M.util.complete(M.reading, max_completions)
+    if #readings == 0 then
+      return
+    end
+    M.completion = {
+      readings = readings,
+      index = 0,
+      original = M.reading,
+    }
+  end
+  local c = M.completion
+  c.index = c.index % (#c.readings + 1) + 1
+
+  -- wrap around to the original reading after the last completion
+  local reading = c.readings[c.index]
+  local next_reading = reading and g_common.split_str(reading) or c.original
+
+  g_common.delete_n_chars_before_cursor(
+    get_reading_len(), 0, g_common.join_str_array(next_reading)
+  )
+  M.reading = next_reading
+end
+
 function M.handle_bs()
+  M.completion = nil
   if #M.reading > 0 then
     if M.curr_input_mode == InputMode.Reading then
       -- delete the last char
@@ -104,6 +140,7 @@ function M.handle_bs()
 end
 
 function M.handle_esc()
+  M.completion = nil
   if M.curr_input_mode == InputMode.Reading then
     -- clear the reverse triangle, reading and incomplete spelling
     local x = #'▽' + get_reading_len() + g_kana_tree.curr_depth
@@ -181,6 +218,11 @@ local function handle_input_reading_mode(c)
       ac_kana_letter = '',
       ac_kana_first_char = ' ',  -- ' ' means None
     })
+  elseif c:match('^%d$') and g_kana_tree.at_the_root_node() then
+    -- digits are kept as they are for numeric conversion (e.g. 12がつ)
+    table.insert(M.reading, c)
+    return c
+
   else
     local res = g_kana_tree.traverse(g_kana_tree, M.handle_input, c)
     local value = res["value"]
@@ -230,6 +272,7 @@ local function handle_input_ac_kana(c)
 end
 
 function M.handle_input(c)
+  M.completion = nil
   if M.curr_input_mode == InputMode.Reading then
     return handle_input_reading_mode(c)
 
@@ -243,6 +286,7 @@ end
 
 function M.enter(inst)
   inst = inst or {}
+  M.completion = nil
   if inst.ac_kana_letter and #inst.ac_kana_letter ~= 0 then
     -- there was no candidate and came back from select kanji state
     M.curr_input_mode = InputMode.AcKana
@@ -1,5 +1,6 @@
 local M = {
   candidates = {},
+  annotations = {},
   curr_index = 0,
   reading = {},
   buffer = nil,
@@ -63,8 +64,13 @@ local function build_selector_line()
   local line = ''
 
   for i=M.curr_index, last_index do
+    local candidate = M.candidates[i + 1]
+    local annotation = M.annotations[i + 1] or ''
+    if #annotation > 0 then
+      candidate = candidate .. ';' .. annotation
+    end
     line = string.format('%s %s: %s',
-      line, selectors[letter_index], M.candidates[i + 1]
+      line, selectors[letter_index], candidate
     )
     letter_index = letter_index + 1
   end
@@ -79,8 +85,10 @@ end
 function M.enter(inst)
   M.util.set_dfa_state(M.util.DFAState.SelectKanjiList)
   M.candidates = inst.candidates
+  M.annotations = inst.annotations or {}
   M.curr_index = 0
   M.reading = inst.reading
+  M.commit = inst.commit
 
   local line = build_selector_line()
   local cursor = vim.api.nvim_win_get_cursor(0)
@@ -98,6 +106,7 @@ local function select_candidate_head_and_go_to_direct_input_kana()
   hide_selector()
 
   local candidate_head = get_candidate_head()
+  M.commit(candidate_head)
   g_common.delete_n_chars_before_cursor(
     #'▼' + #candidate_head,
     0,
@@ -126,6 +135,9 @@ local function go_back_to_input_reading()
   })
 end
 
+function M.handle_tab()
+end
+
 function M.handle_bs()
   local next_index = M.curr_index - #selectors
   if next_index >= 0 then
@@ -178,6 +190,7 @@ function M.handle_input(c)
       -- select candidate
       local candidate = M.candidates[M.curr_index + offset + 1]
       local candidate_head_len = #M.candidates[M.curr_index + 1]
+      M.commit(candidate)
 
       g_common.delete_n_chars_before_cursor(
         #'▼' + candidate_head_len,
@@ -1,7 +1,11 @@
 local M = {
+  last_build_state = nil,
+  -- result set of the last look_up
+  results = nil,
   curr_candidate_index = 0,
   candidates = {},
   list_candidates = {},
+  list_annotations = {},
 }
 
 local g_common = require 'common'
@@ -10,9 +14,40 @@ local g_ffi = require 'ffi'
 local single_selection_up_to = 4
 
 g_ffi.cdef[[
-  int build(const char* dict_file_path);
-  void look_up(char** chars, char ac_kana, const size_t num_chars);
-  void get_results(char** results, const size_t buf_size, const size_t offset, size_t* num_results);
+  typedef struct {
+    int state;
+    size_t num_dicts_done;
+    size_t num_dicts;
+    size_t num_bytes_done;
+    size_t num_bytes;
+  } BuildProgress;
+
+  typedef struct DictAgent DictAgent;
+  typedef struct LookUpResults LookUpResults;
+
+  DictAgent* dict_create();
+  void dict_destroy(DictAgent* agent);
+  int dict_build(DictAgent* agent, char** dict_file_paths, const size_t num_paths, int* results);
+  void dict_get_build_progress(DictAgent* agent, BuildProgress* progress);
+  int dict_load_user_dict(DictAgent* agent, const char* user_dict_file_path);
+  LookUpResults* dict_look_up(DictAgent* agent, const char* reading, const char* okuri);
+  LookUpResults* dict_complete(DictAgent* agent, const char* prefix, const size_t limit);
+  void dict_set_reverse_index(DictAgent* agent, bool enabled);
+  void dict_set_lazy_load(DictAgent* agent, bool enabled);
+  LookUpResults* dict_reverse_look_up(DictAgent* agent, const char* candidate);
+  void dict_commit_candidate(DictAgent* agent, LookUpResults* results, const char* reading, const char* okuri, const char* candidate);
+  int dict_register_word(DictAgent* agent, const char* reading, const char* okuri, const char* word);
+  int dict_purge_candidate(DictAgent* agent, LookUpResults* results, const char* reading, const char* okuri, const char* candidate);
+  int dict_save_user_dict(DictAgent* agent);
+  size_t dict_get_num_warnings(DictAgent* agent);
+  void dict_get_warnings(DictAgent* agent, char** warnings, const size_t buf_size, const size_t offset, size_t* num_warnings);
+  size_t results_count(LookUpResults* results);
+  char* results_get_string(LookUpResults* results, const size_t index, size_t* len);
+  char* results_get_annotation_string(LookUpResults* results, const size_t index, size_t* len);
+  char* results_get_okuri_string(LookUpResults* results, const size_t index, size_t* len);
+  void free_string(char* s);
+  void results_destroy(LookUpResults* results);
+  int get_last_error(char* message, const size_t buf_size);
 ]]
 
 local file_dir = debug.getinfo(1, 'S').source:match("@?(.*/)")
@@ -28,25 +63,41 @@ end
 
 local g_dict = g_ffi.load(file_dir .. '../../rust/target/release/libminskk.' .. lib_ext)
 
+-- the agent holding the dictionaries. destroyed along w/ the module
+local g_agent = g_ffi.gc(g_dict.dict_create(), g_dict.dict_destroy)
+
 local BuildResult = {
   Succeeded = 0,
   FileNotFound = 1,
   MalformedPath = 2,
+  Failed = 3,
 }
 
-function M.build_dict(dict_file_path)
-  local ffi_dict_file_path = g_ffi.new('char[?]', #dict_file_path + 1)
-  g_ffi.copy(ffi_dict_file_path, dict_file_path, #dict_file_path)
+-- returns the strings as char** along with the buffers
+-- that need to be kept alive while char** is in use
+local function to_ffi_strings(strs)
+  local chars = g_ffi.new("char*[?]", #strs)
+  local bufs = {}
 
-  local res = g_dict.build(ffi_dict_file_path)
+  for i = 1, #strs do
+      -- + 1 for null termination. no need to set 0 since luajit zero-fills the array
+      bufs[i] = g_ffi.new("char[?]", #strs[i] + 1)
+      g_ffi.copy(bufs[i], strs[i])
+      chars[i-1] = bufs[i]
+  end
+  return chars, bufs
+end
 
+local function alert_build_result(res, file_path)
   if res ~= BuildResult.Succeeded then
     local msg = 'MinSKK: '
 
     if res == BuildResult.FileNotFound then
-      msg = msg .. dict_file_path .. ' not found'
-    elseif res == BuildResult.PathMalformed then
-      msg = msg .. dict_file_path .. ' is malformed'
+      msg = msg .. file_path .. ' not found'
+    elseif res == BuildResult.MalformedPath then
+      msg = msg .. file_path .. ' is malformed'
+    elseif res == BuildResult.Failed then
+      msg = msg .. 'failed to load ' .. file_path
     else
       error('should not be visited. check code (select-kanji 1)')
     end
@@ -54,54 +105,275 @@ function M.build_dict(dict_file_path)
   end
 end
 
+-- shows the errors queued by the dictionary library if any
+function M.alert_errors()
+  local buf_size = 512
+  local buf = g_ffi.new('char[?]', buf_size)
+  local messages = {}
+
+  -- 0 means no more error
+  while g_dict.get_last_error(buf, buf_size) ~= 0 do
+    -- errors are returned from the oldest
+    table.insert(messages, g_ffi.string(buf))
+  end
+  if #messages > 0 then
+    g_common.alert('MinSKK: ' .. table.concat(messages, ' / '), 5000)
+  end
+end
+
+-- dict_file_paths: dictionary file paths in the order of priority
+-- reverse_index: whether to build the indexes for reverse_look_up
+-- lazy_load: whether to look up uncompressed dictionaries in the files instead of loading them
+function M.build_dict(dict_file_paths, reverse_index, lazy_load)
+  g_dict.dict_set_reverse_index(g_agent, reverse_index or false)
+  g_dict.dict_set_lazy_load(g_agent, lazy_load or false)
+
+  local paths, _bufs = to_ffi_strings(dict_file_paths)
+  local results = g_ffi.new('int[?]', #dict_file_paths)
+
+  g_dict.dict_build(g_agent, paths, #dict_file_paths, results)
+
+  for i, dict_file_path in ipairs(dict_file_paths) do
+    alert_build_result(results[i-1], dict_file_path)
+  end
+  M.alert_errors()
+end
+
+local BuildState = {
+  NotStarted = 0,
+  LoadingCache = 1,
+  Parsing = 2,
+  Serializing = 3,
+  Ready = 4,
+  Failed = 5,
+}
+
+-- returns the message describing the dictionaries being built
+-- or nil if they are ready or not requested to build
+function M.get_build_status()
+  local progress = g_ffi.new('BuildProgress[1]')
+  g_dict.dict_get_build_progress(g_agent, progress)
+  local p = progress[0]
+
+  -- errors of the build are known once it finishes
+  if p.state ~= M.last_build_state then
+    M.last_build_state = p.state
+    if p.state == BuildState.Ready or p.state == BuildState.Failed then
+      M.alert_errors()
+    end
+  end
+
+  if p.state == BuildState.NotStarted or p.state == BuildState.Ready then
+    return nil
+  elseif p.state == BuildState.Failed then
+    return '辞書読込失敗'
+  elseif p.state == BuildState.Serializing then
+    return '辞書保存中'
+  end
+
+  -- each dictionary accounts for the same share
+  local done = tonumber(p.num_dicts_done)
+  if p.num_bytes > 0 then
+    done = done + tonumber(p.num_bytes_done) / tonumber(p.num_bytes)
+  end
+  local percent = math.floor(done / math.max(tonumber(p.num_dicts), 1) * 100)
+  return '辞書読込中 ' .. percent .. '%'
+end
+
+function M.load_user_dict(user_dict_file_path)
+  local ffi_path = g_ffi.new('char[?]', #user_dict_file_path + 1)
+  g_ffi.copy(ffi_path, user_dict_file_path, #user_dict_file_path)
+
+  local res = g_dict.dict_load_user_dict(g_agent, ffi_path)
+  alert_build_result(res, user_dict_file_path)
+  M.alert_errors()
+end
+
+-- writes the changes of the user dictionary that are otherwise saved a while after they are made
+function M.save_user_dict()
+  g_dict.dict_save_user_dict(g_agent)
+  M.alert_errors()
+end
+
 function M.init(dfa, util)
   M.dfa = dfa
   M.util = util
 end
 
-local function look_up(reading, ac_kana_letter, ac_kana_first_char)
-  local chars = g_ffi.new("char*[?]", #reading)
-
-  for i = 1, #reading do
-      -- + 1 for null termination. no need to set 0 since luajit zero-fills the array
-      chars[i-1] = g_ffi.new("char[?]", #reading[i] + 1)
-      g_ffi.copy(chars[i-1], reading[i])
+-- returns the string allocated by the dictionary library and frees it.
+-- nil is returned for an index out of range
+local function take_string(ptr, len)
+  if ptr == nil then
+    return nil
   end
+  local str = g_ffi.string(ptr, len[0])
+  g_dict.free_string(ptr)
+  return str
+end
 
-  local ac_kana = g_ffi.new("char[1]", ac_kana_first_char:byte())
-  g_dict.look_up(chars, ac_kana[0], #reading)
-
-  local buf_size = 50
-  local offset = 0
-  local num_bufs = 10
-  local num_results = g_ffi.new("size_t[1]", num_bufs)
+-- returns all the strings of the result set using
+-- results_get_string or results_get_annotation_string as the getter
+local function get_results(results, getter)
+  local len = g_ffi.new('size_t[1]')
+  local strs = {}
 
-  local results = g_ffi.new("char*[?]", num_bufs)
-  for i = 1, num_bufs do
-      results[i-1] = g_ffi.new("char[?]", buf_size)
+  for i = 0, tonumber(g_dict.results_count(results)) - 1 do
+    table.insert(strs, take_string(getter(results, i, len), len) or '')
   end
+  return strs
+end
+
+-- returns the okuri passed to the dictionary library.
+-- it is the romaji prefix followed by the okuri kana such as 'sし'
+-- and ' ' as the prefix means okuri-nasi
+local function to_okuri(ac_kana_first_char, ac_kana_letter)
+  return ac_kana_first_char .. ac_kana_letter
+end
 
-  g_dict.get_results(
-    results,
-    buf_size,
-    offset,
-    num_results
-  );
+local function look_up(reading, ac_kana_first_char, ac_kana_letter)
+  local okuri = to_okuri(ac_kana_first_char, ac_kana_letter)
+
+  -- kept to commit or purge the candidate selected from the results
+  M.results = g_ffi.gc(
+    g_dict.dict_look_up(g_agent, g_common.join_str_array(reading), okuri),
+    g_dict.results_destroy
+  )
 
   M.candidates = {};
   M.list_candidates = {};
+  M.list_annotations = {};
 
-  local result_end_index = tonumber(num_results[0]) or 0
-
-  for i = 1, result_end_index do
-    local candidate = g_ffi.string(results[i-1])
+  local annotations = get_results(M.results, g_dict.results_get_annotation_string)
 
+  for i, candidate in ipairs(get_results(M.results, g_dict.results_get_string)) do
     if i <= single_selection_up_to then
-      table.insert(M.candidates, candidate .. ac_kana_letter)
+      table.insert(M.candidates, candidate .. M.ac_kana_letter)
     else
-      table.insert(M.list_candidates, candidate .. ac_kana_letter)
+      table.insert(M.list_candidates, candidate .. M.ac_kana_letter)
+      table.insert(M.list_annotations, annotations[i] or '')
     end
   end
+  -- null is returned instead of the results if the lookup failed
+  M.alert_errors()
+end
+
+-- returns readings starting with the reading
+function M.complete(reading, limit)
+  local results = g_dict.dict_complete(g_agent, g_common.join_str_array(reading), limit)
+  local readings = get_results(results, g_dict.results_get_string)
+  g_dict.results_destroy(results)
+  return readings
+end
+
+-- returns the readings of the word in the form of { reading = 'くや', okuri = 'sし' }.
+-- okuri is '' for okuri-nasi readings
+function M.reverse_look_up(word)
+  local results = g_dict.dict_reverse_look_up(g_agent, word)
+  local readings = get_results(results, g_dict.results_get_string)
+  local okuris = get_results(results, g_dict.results_get_okuri_string)
+  g_dict.results_destroy(results)
+  M.alert_errors()
+
+  local entries = {}
+  for i, reading in ipairs(readings) do
+    table.insert(entries, { reading = reading, okuri = okuris[i] })
+  end
+  return entries
+end
+
+-- returns the dictionary lines skipped while building in the form of path:line number: reason
+function M.get_warnings()
+  local num_warnings = tonumber(g_dict.dict_get_num_warnings(g_agent))
+  if num_warnings == 0 then
+    return {}
+  end
+
+  local buf_size = 512
+  local num_results = g_ffi.new("size_t[1]", num_warnings)
+  local warnings = g_ffi.new("char*[?]", num_warnings)
+  local bufs = {}
+  for i = 1, num_warnings do
+      bufs[i] = g_ffi.new("char[?]", buf_size)
+      warnings[i-1] = bufs[i]
+  end
+  g_dict.dict_get_warnings(g_agent, warnings, buf_size, 0, num_results)
+
+  local strs = {}
+  for i = 1, tonumber(num_results[0]) do
+    table.insert(strs, g_ffi.string(warnings[i-1]))
+  end
+  return strs
+end
+
+-- lets the dictionary learn the candidate selected by the user
+local function commit(candidate)
+  local okuri = to_okuri(M.ac_kana_first_char, M.ac_kana_letter)
+
+  -- drop the accompanying kana appended to the candidate
+  local kanji = candidate:sub(1, #candidate - #M.ac_kana_letter)
+  g_dict.dict_commit_candidate(
+    g_agent, M.results, g_common.join_str_array(M.reading), okuri, kanji
+  )
+  M.alert_errors()
+end
+
+local UserDictResult = {
+  Succeeded = 0,
+  UserDictNotLoaded = 1,
+  Failed = 2,
+}
+
+-- removes the candidate from the dictionary so that it is no longer offered
+local function purge(candidate)
+  local okuri = to_okuri(M.ac_kana_first_char, M.ac_kana_letter)
+
+  local kanji = candidate:sub(1, #candidate - #M.ac_kana_letter)
+  local res = g_dict.dict_purge_candidate(
+    g_agent, M.results, g_common.join_str_array(M.reading), okuri, kanji
+  )
+
+  if res == UserDictResult.Succeeded then
+    M.util.status.show_alert('削除: ' .. kanji)
+  elseif res == UserDictResult.UserDictNotLoaded then
+    M.util.status.show_alert('MinSKK: user dictionary is not loaded', 5000)
+  else
+    M.util.status.show_alert('MinSKK: failed to purge ' .. kanji, 5000)
+    M.alert_errors()
+  end
+end
+
+-- asks the user for a word of the reading that has no candidate
+-- and adds it to the user dictionary
+local function register_word(inst)
+  local reading = inst.reading
+  local ac_kana_letter = inst.ac_kana_letter
+  local ac_kana_first_char = inst.ac_kana_first_char
+  local prompt = '[辞書登録] ' .. g_common.join_str_array(reading)
+  if #ac_kana_letter > 0 then
+    prompt = prompt .. '*' .. ac_kana_letter
+  end
+
+  -- cannot prompt while handling the input
+  vim.schedule(function()
+    vim.ui.input({ prompt = prompt .. ': ' }, function(word)
+      if not word or #word == 0 then
+        return
+      end
+      local okuri = to_okuri(ac_kana_first_char, ac_kana_letter)
+      local res = g_dict.dict_register_word(
+        g_agent, g_common.join_str_array(reading), okuri, word
+      )
+
+      if res == UserDictResult.Succeeded then
+        M.util.status.show_alert('登録: ' .. word)
+      elseif res == UserDictResult.UserDictNotLoaded then
+        M.util.status.show_alert('MinSKK: user dictionary is not loaded', 5000)
+      else
+        M.util.status.show_alert('MinSKK: failed to register ' .. word, 5000)
+        M.alert_errors()
+      end
+    end)
+  end)
 end
 
 local function get_curr_candidate()
@@ -121,7 +393,9 @@ local function get_next_candidate(first_time)
 
     M.dfa.go_to_select_kanji_list_state({
       candidates = M.list_candidates,
+      annotations = M.list_annotations,
       reading = M.reading,
+      commit = commit,
     })
     -- not updating curr_candidate_index to be able to come back
     return M.list_candidates[1]
@@ -144,15 +418,20 @@ local function get_prev_candidate()
 end
 
 function M.handle_ctrl_j()
+  commit(get_curr_candidate())
   remove_inverted_triangle()
   M.dfa.go_to_direct_input_kana_state()
 end
 
 function M.handle_cr()
+  commit(get_curr_candidate())
   remove_inverted_triangle()
   M.dfa.go_to_direct_input_kana_state()
 end
 
+function M.handle_tab()
+end
+
 function M.handle_bs()
   -- show the previuos kanji candidate
   local candidate = get_prev_candidate()
@@ -180,13 +459,37 @@ function M.handle_input(c)
 
   elseif c == ';' then
     -- select the current candidate
+    commit(get_curr_candidate())
     remove_inverted_triangle()
 
     -- start entering the next readings
     M.dfa.go_to_input_reading_state()
     return '▽'
 
+  elseif c == 'X' then
+    -- purge the current candidate and go back to the input reading state
+    -- once the user confirms it, otherwise stay on the candidate
+    local candidate = get_curr_candidate()
+    local results = M.results
+    local kanji = candidate:sub(1, #candidate - #M.ac_kana_letter)
+
+    -- cannot prompt while handling the input
+    vim.schedule(function()
+      local answer = vim.fn.confirm(
+        'Really purge ' .. kanji .. '?', '&Yes\n&No', 2
+      )
+      -- the candidate may have been left before prompting
+      if answer ~= 1 or M.results ~= results
+        or get_curr_candidate() ~= candidate then
+        return
+      end
+      purge(candidate)
+      M.handle_esc()
+    end)
+    return ''
+
   else
+    commit(get_curr_candidate())
     remove_inverted_triangle()
     M.dfa.go_to_direct_input_kana_state()
     vim.api.nvim_feedkeys(c, "in", true)
@@ -198,13 +501,21 @@ end
 -- or returns nil and goes back to input reading state
 -- in case no candidate is found
 function M.enter(inst)
+  M.ac_kana_letter = inst.ac_kana_letter
+  M.ac_kana_first_char = inst.ac_kana_first_char
   look_up(
     inst.reading,
-    inst.ac_kana_letter,
-    inst.ac_kana_first_char
+    inst.ac_kana_first_char,
+    inst.ac_kana_letter
   )
   if #M.candidates == 0 then
-    -- TODO support word registration
+    -- nothing can be found until the dictionaries are ready
+    local build_status = M.get_build_status()
+    if build_status then
+      M.util.status.show_alert('MinSKK: ' .. build_status)
+    else
+      register_word(inst)
+    end
     M.dfa.go_to_input_reading_state(inst)
     return nil
   end
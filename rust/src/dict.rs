@@ -1,60 +1,381 @@
+use crate::{
+  encoding::{self, DictEncoding},
+  error,
+  lisp,
+  vec_map::VecMap,
+};
+
 use bincode;
-use encoding_rs::EUC_JP;
 use flate2::{
   Compression,
   write::{GzDecoder, GzEncoder},
 };
 use serde::{Serialize, Deserialize};
 use std::{
-  collections::HashMap,
+  collections::{HashMap, VecDeque},
+  fmt::{self, Write as _},
   fs::File,
-  io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write},
+  io::{BufRead, BufReader, Cursor, Error, ErrorKind, Read, Result, Write},
+  mem,
   path::PathBuf,
+  sync::mpsc,
+  thread,
 };
 
+// kanji w/ the annotation following ';' in the dictionary
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+  pub kanji: String,
+  pub annotation: Option<String>,
+}
+
+// node of the trie keyed by the reading chars.
+// the maps are sorted vectors as most nodes have a few entries or none
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Node {
-  children: HashMap<char,Node>,
-  kanjis: HashMap<Option<char>, Vec<String>>,
+  children: VecMap<char, Node>,
+  kanjis: VecMap<Option<char>, Vec<Candidate>>,
+  // kanjis of okuri blocks such as [し/悔/] keyed by the accompanying kana and the okuri kana
+  okuri_kanjis: VecMap<(char, String), Vec<Candidate>>,
+}
+
+// reading that a candidate is found for. okuri is the okuri kana of the okuri block
+// the candidate is in such as し of [し/悔/] and empty if it is not in any
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ReverseEntry {
+  pub reading: String,
+  pub acc_kana: Option<char>,
+  pub okuri: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Dict {
   root: Node,
+  // encoding of the source file
+  encoding: DictEncoding,
+  // readings of each candidate. built on request as it takes as much memory as the tree.
+  // dropped once the dictionary is modified
+  reverse_index: Option<HashMap<String, Vec<ReverseEntry>>>,
 }
 
+// written in front of a serialized dictionary.
+// bump the version whenever the layout of Dict changes so that stale files are rebuilt
+const SER_MAGIC: [u8; 4] = *b"MSKK";
+const SER_VERSION: u32 = 7;
+
+// recorded in the caches of dictionaries w/ the layout version.
+// bump the version whenever the way lines are parsed changes
+// so that the caches built by another parser are rebuilt.
+// 1: Cyrillic readings are no longer split into okuri
+pub const PARSER_VERSION: u32 = 1;
+
+// # of lines parsed between progress notifications
+const PROGRESS_INTERVAL: usize = 1024;
+
+// # of lines sent to a thread building the dictionary at once
+const BATCH_LEN: usize = 1024;
+// # of batches waiting for each thread. bounds the memory when the threads fall behind
+const MAX_PENDING_BATCHES: usize = 4;
+
 impl Node {
   pub fn new() -> Self {
     Self {
-      children: HashMap::<char,Node>::new(),
-      kanjis: HashMap::<Option<char>, Vec::<String>>::new(),
+      children: VecMap::new(),
+      kanjis: VecMap::new(),
+      okuri_kanjis: VecMap::new(),
+    }
+  }
+
+  // drops the capacity left by building the tree
+  fn shrink_to_fit(&mut self) {
+    self.children.shrink_to_fit();
+    self.kanjis.shrink_to_fit();
+    self.okuri_kanjis.shrink_to_fit();
+    for kanjis in self.kanjis.values_mut().chain(self.okuri_kanjis.values_mut()) {
+      kanjis.shrink_to_fit();
+    }
+    for child in self.children.values_mut() {
+      child.shrink_to_fit();
+    }
+  }
+
+  // moves the entries of the other node.
+  // the readings of the nodes should not share the first char
+  fn append(&mut self, other: Node) {
+    self.children.append(other.children);
+    self.kanjis.append(other.kanjis);
+    self.okuri_kanjis.append(other.okuri_kanjis);
+    self.children.shrink_to_fit();
+    self.kanjis.shrink_to_fit();
+    self.okuri_kanjis.shrink_to_fit();
+  }
+
+  fn add_memory_usage(&self, usage: &mut MemoryUsage) {
+    let candidates_size = |kanjis: &Vec<Candidate>| {
+      kanjis.capacity() * mem::size_of::<Candidate>()
+        + kanjis.iter()
+          .map(|x| x.kanji.capacity() + x.annotation.as_ref().map_or(0, |x| x.capacity()))
+          .sum::<usize>()
+    };
+
+    usage.num_nodes += 1;
+    usage.node_bytes += self.children.heap_size() + self.kanjis.heap_size() + self.okuri_kanjis.heap_size();
+    for kanjis in self.kanjis.values() {
+      usage.num_candidates += kanjis.len();
+      usage.candidate_bytes += candidates_size(kanjis);
     }
+    for ((_, okuri), kanjis) in &self.okuri_kanjis {
+      usage.num_candidates += kanjis.len();
+      usage.candidate_bytes += okuri.capacity() + candidates_size(kanjis);
+    }
+    for child in self.children.values() {
+      child.add_memory_usage(usage);
+    }
+  }
+}
+
+// estimated memory taken by the tree of a dictionary
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+  pub num_nodes: usize,
+  pub num_candidates: usize,
+  // bytes of the nodes and their maps
+  pub node_bytes: usize,
+  // bytes of the candidate lists and the strings
+  pub candidate_bytes: usize,
+}
+
+impl MemoryUsage {
+  pub fn total_bytes(&self) -> usize {
+    self.node_bytes + self.candidate_bytes
+  }
+}
+
+impl fmt::Display for MemoryUsage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let to_mib = |x: usize| x as f64 / (1024.0 * 1024.0);
+    write!(
+      f,
+      "{} nodes: {:.1} MiB, {} candidates: {:.1} MiB, total: {:.1} MiB",
+      self.num_nodes,
+      to_mib(self.node_bytes),
+      self.num_candidates,
+      to_mib(self.candidate_bytes),
+      to_mib(self.total_bytes()),
+    )
+  }
+}
+
+impl Default for Node {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Default for Dict {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Candidate {
+  pub fn new(kanji: &str) -> Self {
+    Candidate {
+      kanji: kanji.to_owned(),
+      annotation: None,
+    }
+  }
+
+  // parses a candidate in the form of kanji[;annotation]
+  pub fn parse(s: &str) -> Self {
+    match s.split_once(';') {
+      Some((kanji, annotation)) => Candidate {
+        kanji: kanji.to_owned(),
+        annotation: Some(annotation.to_owned()),
+      },
+      None => Candidate::new(s),
+    }
+  }
+
+  // adds the candidate to the list unless the list already has the kanji.
+  // the annotation is taken over if the existing one doesn't have it
+  pub fn add_unique(candidates: &mut Vec<Candidate>, candidate: &Candidate) {
+    match candidates.iter_mut().find(|x| x.kanji == candidate.kanji) {
+      Some(x) => {
+        if x.annotation.is_none() {
+          x.annotation.clone_from(&candidate.annotation);
+        }
+      },
+      None => candidates.push(candidate.clone()),
+    }
+  }
+}
+
+// formats the candidate as in the dictionary line
+impl std::fmt::Display for Candidate {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let kanji = lisp::quote(&self.kanji);
+    match &self.annotation {
+      Some(annotation) => write!(f, "{};{}", kanji, annotation),
+      None => write!(f, "{}", kanji),
+    }
+  }
+}
+
+impl PartialEq<&str> for Candidate {
+  fn eq(&self, other: &&str) -> bool {
+    self.kanji == *other
+  }
+}
+
+// SKK-JISYO files list okuri-ari entries and okuri-nasi entries
+// in the sections starting w/ the markers below
+const OKURI_ARI_MARKER: &str = ";; okuri-ari entries.";
+const OKURI_NASI_MARKER: &str = ";; okuri-nasi entries.";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Section {
+  // no marker seen yet. okuri is guessed from the reading
+  Unknown,
+  OkuriAri,
+  OkuriNasi,
+}
+
+impl Section {
+  // returns the section starting at the line or None if the line is not a marker
+  pub fn from_marker(line: &str) -> Option<Section> {
+    match line.trim_end() {
+      OKURI_ARI_MARKER => Some(Section::OkuriAri),
+      OKURI_NASI_MARKER => Some(Section::OkuriNasi),
+      _ => None,
+    }
+  }
+}
+
+// line skipped while building a dictionary
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseWarning {
+  // 1-based
+  pub line_num: usize,
+  pub reason: String,
+}
+
+// dictionary being built line by line
+struct DictBuilder {
+  dict: Dict,
+  section: Section,
+  warnings: Vec<ParseWarning>,
+  num_lines: usize,
+}
+
+impl DictBuilder {
+  fn new() -> Self {
+    DictBuilder {
+      dict: Dict::new(),
+      section: Section::Unknown,
+      warnings: vec![],
+      num_lines: 0,
+    }
+  }
+
+  fn add_line(&mut self, line: &str) {
+    self.num_lines += 1;
+    match Section::from_marker(Dict::normalize_line(line)) {
+      Some(x) => self.section = x,
+      None => self.add_entry(self.num_lines, self.section, line),
+    }
+  }
+
+  // line_num: 1-based line number in the file
+  fn add_entry(&mut self, line_num: usize, section: Section, line: &str) {
+    if let Err(e) = self.dict.add_dict_file_line_in_section(line, section) {
+      self.warnings.push(ParseWarning {
+        line_num,
+        reason: e.to_string(),
+      });
+    }
+  }
+
+  fn finish(mut self) -> (Dict, Vec<ParseWarning>) {
+    self.dict.root.shrink_to_fit();
+    (self.dict, self.warnings)
   }
 }
 
 struct ParseResult {
   pub readings: Vec<char>,
-  pub kanjis: Vec<String>,
+  pub kanjis: Vec<Candidate>,
+  // pairs of the okuri kana and the kanjis in the okuri block
+  pub okuri_blocks: Vec<(String, Vec<Candidate>)>,
 }
 
 impl Dict {
   pub fn new() -> Self {
-    Dict { root: Node::new() }
+    Dict {
+      root: Node::new(),
+      encoding: DictEncoding::default(),
+      reverse_index: None,
+    }
+  }
+
+  pub fn encoding(&self) -> DictEncoding {
+    self.encoding
+  }
+
+  pub fn set_encoding(&mut self, encoding: DictEncoding) {
+    self.encoding = encoding;
   }
   
   fn is_alphabet(c: &char) -> bool {
-    (c >= &'a' && c <= &'z') || (c >= &'A' && c <= &'Z')
+    c.is_ascii_alphabetic()
   }
 
-  fn parse_line(&self, line: &str) -> Option<ParseResult> {
-    if line.starts_with(";;") { // ignore comment
+  // returns the accompanying kana of the readings if the entry is okuri-ari
+  fn get_acc_kana(readings: &[char], section: Section) -> Option<char> {
+    let (last_char, stem) = readings.split_last()?;
+    if !Dict::is_alphabet(last_char) {
       return None;
     }
+    match section {
+      Section::OkuriAri => Some(*last_char),
+      Section::OkuriNasi => None,
+      // readings consisting only of ascii chars such as 'Cyrillic' are abbrevs
+      Section::Unknown => {
+        if stem.iter().any(|c| !c.is_ascii()) {
+          Some(*last_char)
+        } else {
+          None
+        }
+      },
+    }
+  }
 
-    let toks: Vec<&str> = line.splitn(2, ' ').collect();
+  // removes the BOM, CR of CRLF and trailing spaces
+  fn normalize_line(line: &str) -> &str {
+    line.trim_start_matches('\u{feff}').trim_end()
+  }
+
+  // returns None for comments and blank lines
+  fn parse_line(&self, line: &str) -> Result<Option<ParseResult>> {
+    let line = Dict::normalize_line(line);
+    if line.is_empty() || line.starts_with(";;") { // ignore comment
+      return Ok(None);
+    }
+
+    // the reading is followed by a space or a tab
+    let toks: Vec<&str> = line.splitn(2, [' ', '\t']).collect();
     if toks.len() != 2 {
-      println!("Unexpected dict line: {line}");
-      return None;
+      return Err(Error::new(
+        ErrorKind::InvalidData,
+        "No space between the reading and the candidates",
+      ));
+    }
+    let toks = [toks[0], toks[1].trim_start()];
+    if !toks[1].starts_with('/') {
+      return Err(Error::new(
+        ErrorKind::InvalidData,
+        "Candidates should start w/ '/'",
+      ));
     }
     
     // parse readings and accompanying kana part
@@ -64,78 +385,460 @@ impl Dict {
       readings.push(c);
     }
     
-    // parse kanji part
+    let (kanjis, okuri_blocks) = Dict::parse_candidates(toks[1]);
+
+    Ok(Some(ParseResult {
+      readings,
+      kanjis,
+      okuri_blocks,
+    }))
+  }
+
+  // parses the candidates surrounded by '/'s and returns them along w/ the okuri blocks.
+  // okuri blocks look like /[し/悔/]/ where し is the okuri kana
+  pub fn parse_candidates(s: &str) -> (Vec<Candidate>, Vec<(String, Vec<Candidate>)>) {
     let mut kanjis = vec![];
+    let mut okuri_blocks: Vec<(String, Vec<Candidate>)> = vec![];
+    let mut in_okuri_block = false;
 
-    // toks[1] is surrounded by '/'s
-    for tok in toks[1].split('/') {
-      if tok.len() > 0 {
-        // drop annotation if exists
-        let toks: Vec<&str> = tok.splitn(2, ';').collect();
-        kanjis.push(toks[0].to_owned()); 
+    for tok in s.split('/') {
+      if tok.is_empty() {
+        continue;
+      }
+      if let Some(okuri) = tok.strip_prefix('[') {
+        okuri_blocks.push((okuri.to_owned(), vec![]));
+        in_okuri_block = true;
+
+      } else if in_okuri_block && tok == "]" {
+        in_okuri_block = false;
+
+      } else if in_okuri_block {
+        let candidate = Candidate::parse(tok);
+        // kanjis in okuri blocks should also be found w/o the okuri kana
+        Candidate::add_unique(&mut kanjis, &candidate);
+        okuri_blocks.last_mut().unwrap().1.push(candidate);
+
+      } else {
+        kanjis.push(Candidate::parse(tok));
       }
     }
+    (kanjis, okuri_blocks)
+  }
 
-    Some(ParseResult {
-      readings,
-      kanjis,
-    })
+  // same as look_up_okuri_strict but for the candidates in the dictionary file
+  pub fn parse_candidates_for_okuri(s: &str, acc_kana: &Option<char>, okuri: &str) -> Vec<Candidate> {
+    let (kanjis, okuri_blocks) = Dict::parse_candidates(s);
+
+    let mut res = okuri_blocks.into_iter()
+      .find(|(x, _)| acc_kana.is_some() && x == okuri)
+      .map(|(_, xs)| xs)
+      .unwrap_or_default();
+    for kanji in &kanjis {
+      Candidate::add_unique(&mut res, kanji);
+    }
+    res
   }
-  
+
+  // same as collect_reverse_entries but for the entry in the dictionary file such as
+  // くやs /悔/[し/悔/]/. key is the reading followed by the accompanying kana if okuri-ari
+  pub fn collect_reverse_entries_of_line(
+    key: &str,
+    s: &str,
+    is_okuri_ari: bool,
+    candidate: &str,
+    entries: &mut Vec<ReverseEntry>,
+  ) {
+    let mut reading: Vec<char> = key.chars().collect();
+    if reading.contains(&'#') {
+      return;
+    }
+    let acc_kana = if is_okuri_ari { reading.pop() } else { None };
+    let reading: String = reading.into_iter().collect();
+    let is_candidate = |x: &Candidate| lisp::render(&x.kanji).is_some_and(|x| x == candidate);
+    let (kanjis, okuri_blocks) = Dict::parse_candidates(s);
+
+    let mut found_in_block = false;
+    if acc_kana.is_some() {
+      for (okuri, xs) in &okuri_blocks {
+        if xs.iter().any(is_candidate) {
+          entries.push(ReverseEntry { reading: reading.clone(), acc_kana, okuri: okuri.clone() });
+          found_in_block = true;
+        }
+      }
+    }
+    if !found_in_block && kanjis.iter().any(is_candidate) {
+      entries.push(ReverseEntry { reading, acc_kana, okuri: String::new() });
+    }
+  }
+
   pub fn add_dict_file_line(&mut self, line: &str) -> Result<()> {
-    match self.parse_line(line) {
+    self.add_dict_file_line_in_section(line, Section::Unknown)
+  }
+
+  pub fn add_dict_file_line_in_section(&mut self, line: &str, section: Section) -> Result<()> {
+    match self.parse_line(line)? {
       None => Ok(()),
       Some(res) => {
-        if res.readings.len() == 0 || res.kanjis.len() == 0 {
+        if res.readings.is_empty() || res.kanjis.is_empty() {
           Err(
             Error::new(
               ErrorKind::NotFound,
-              format!("Malformed line: '{}'", line)
+              "No reading or candidate",
             ))
         } else {
-          let mut node = &mut self.root;
-
           // get accompanying kana first if exists
-          let acc_kana = {
-            let last_char = res.readings.last().unwrap();
-            if Dict::is_alphabet(last_char) {
-              Some(last_char.clone())
-            } else {
-              None
-            }
-          };
+          let acc_kana = Dict::get_acc_kana(&res.readings, section);
           
           // add reading nodes exluding the accompanying kana
           let readings = match acc_kana {
-            None => &res.readings,
+            None => &res.readings[..],
             Some(_) => &res.readings[..res.readings.len() - 1],
           };
-          for c in readings {
-            if node.children.contains_key(&c) {
-              node = node.children.get_mut(&c).unwrap();  
-            } else {
-              let child_node = Node::new();
-              node.children.insert(c.clone(), child_node);
-              node = node.children.get_mut(&c).unwrap();
-            }
-          }
 
           // add kanjis w/ accompanying kana as the key 
           // to the node of the last reading char
-          if !node.kanjis.contains_key(&acc_kana) {
-            node.kanjis.insert(acc_kana, vec![]);
-          }
-          let kanjis = node.kanjis.get_mut(&acc_kana).unwrap();
+          let node = self.get_or_add_node(readings);
+          let kanjis = node.kanjis.get_or_default(acc_kana);
           for x in res.kanjis {
             kanjis.push(x);
           }
+
+          // okuri blocks only make sense for okuri-ari entries
+          if let Some(acc_kana) = acc_kana {
+            for (okuri, xs) in res.okuri_blocks {
+              let kanjis = node.okuri_kanjis.get_or_default((acc_kana, okuri));
+              for x in xs {
+                kanjis.push(x);
+              }
+            }
+          }
           Ok(())
         }
       }
     }
   }
 
-  pub fn look_up(&self, readings: &Vec<char>, acc_kana: &Option<char>) -> Option<&Vec<String>> {
+  // returns the node of the readings adding the reading nodes if missing
+  fn get_or_add_node(&mut self, readings: &[char]) -> &mut Node {
+    self.reverse_index = None;
+    let mut node = &mut self.root;
+
+    for c in readings {
+      node = node.children.get_or_default(*c);
+    }
+    node
+  }
+
+  fn find_node(&self, readings: &[char]) -> Option<&Node> {
+    let mut node = &self.root;
+
+    for c in readings {
+      node = node.children.get(c)?;
+    }
+    Some(node)
+  }
+
+  // returns the kanji list of the reading/accompanying kana pair
+  // adding the reading nodes and an empty list if missing
+  fn get_or_add_kanjis(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+  ) -> &mut Vec<Candidate> {
+    self.get_or_add_node(readings).kanjis.get_or_default(*acc_kana)
+  }
+
+  // moves the kanji to the head of the kanji list of the reading/accompanying kana pair.
+  // the kanji is added at the head if not in the list yet
+  pub fn move_to_front(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    kanji: &str,
+  ) {
+    let kanjis = self.get_or_add_kanjis(readings, acc_kana);
+
+    // keep the annotation of the kanji if any
+    let candidate = match kanjis.iter().position(|x| x.kanji == kanji) {
+      Some(i) => kanjis.remove(i),
+      None => Candidate::new(kanji),
+    };
+    kanjis.insert(0, candidate);
+  }
+
+  // same as move_to_front but also moves the kanji to the head of the okuri block.
+  // the okuri block is not touched if the okuri kana is empty
+  pub fn move_to_front_okuri(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+    kanji: &str,
+  ) {
+    self.move_to_front(readings, acc_kana, kanji);
+
+    if let Some(acc_kana) = acc_kana {
+      if !okuri.is_empty() {
+        let node = self.get_or_add_node(readings);
+        let kanjis = node.okuri_kanjis.get_or_default((*acc_kana, okuri.to_owned()));
+
+        let candidate = match kanjis.iter().position(|x| x.kanji == kanji) {
+          Some(i) => kanjis.remove(i),
+          None => Candidate::new(kanji),
+        };
+        kanjis.insert(0, candidate);
+      }
+    }
+  }
+
+  // appends the kanji to the kanji list of the reading/accompanying kana pair
+  pub fn add(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    kanji: &str,
+  ) {
+    self.get_or_add_kanjis(readings, acc_kana).push(Candidate::new(kanji));
+  }
+
+  // removes the kanji from the kanji list of the reading/accompanying kana pair.
+  // returns false if the kanji is not in the list
+  pub fn remove(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    kanji: &str,
+  ) -> bool {
+    self.reverse_index = None;
+    let mut node = &mut self.root;
+
+    for c in readings {
+      match node.children.get_mut(c) {
+        None => {
+          return false;
+        },
+        Some(x) => {
+          node = x;
+        },
+      };
+    }
+    match node.kanjis.get_mut(acc_kana) {
+      None => false,
+      Some(kanjis) => {
+        let len = kanjis.len();
+        kanjis.retain(|x| x.kanji != kanji);
+        let removed = kanjis.len() != len;
+
+        if kanjis.is_empty() {
+          node.kanjis.remove(acc_kana);
+        }
+
+        // the kanji should not remain in the okuri blocks either
+        if let Some(acc_kana) = acc_kana {
+          for ((x, _), kanjis) in node.okuri_kanjis.iter_mut() {
+            if x == acc_kana {
+              kanjis.retain(|x| x.kanji != kanji);
+            }
+          }
+          node.okuri_kanjis.retain(|_, kanjis| !kanjis.is_empty());
+        }
+        removed
+      },
+    }
+  }
+
+  // adds the readings of the candidates under the node to the index.
+  // candidates are indexed as rendered so that they can be found by the text in a document.
+  // numeric ones are skipped as they cannot be typed as they are
+  fn collect_reverse_entries(
+    node: &Node,
+    readings: &mut String,
+    index: &mut HashMap<String, Vec<ReverseEntry>>,
+  ) {
+    let mut add = |kanji: &str, acc_kana: Option<char>, okuri: &str| {
+      if let Some(rendered) = lisp::render(kanji) {
+        let entry = ReverseEntry {
+          reading: readings.clone(),
+          acc_kana,
+          okuri: okuri.to_owned(),
+        };
+        let entries = index.entry(rendered).or_default();
+        if !entries.contains(&entry) {
+          entries.push(entry);
+        }
+      }
+    };
+
+    if !readings.contains('#') {
+      for ((acc_kana, okuri), kanjis) in &node.okuri_kanjis {
+        for kanji in kanjis {
+          add(&kanji.kanji, Some(*acc_kana), okuri);
+        }
+      }
+      for (acc_kana, kanjis) in &node.kanjis {
+        for kanji in kanjis {
+          // okuri blocks tell more about how to type the kanji
+          let in_okuri_block = acc_kana.is_some_and(|acc_kana| {
+            node.okuri_kanjis.iter()
+              .any(|((x, _), xs)| *x == acc_kana && xs.iter().any(|x| x.kanji == kanji.kanji))
+          });
+          if !in_okuri_block {
+            add(&kanji.kanji, *acc_kana, "");
+          }
+        }
+      }
+    }
+    for (c, child) in &node.children {
+      readings.push(*c);
+      Dict::collect_reverse_entries(child, readings, index);
+      readings.pop();
+    }
+  }
+
+  fn collect_reverse_index(&self) -> HashMap<String, Vec<ReverseEntry>> {
+    let mut index = HashMap::new();
+    Dict::collect_reverse_entries(&self.root, &mut String::new(), &mut index);
+    for entries in index.values_mut() {
+      entries.sort();
+    }
+    index
+  }
+
+  pub fn build_reverse_index(&mut self) {
+    self.reverse_index = Some(self.collect_reverse_index());
+  }
+
+  pub fn has_reverse_index(&self) -> bool {
+    self.reverse_index.is_some()
+  }
+
+  // the reverse index is not included
+  pub fn memory_usage(&self) -> MemoryUsage {
+    let mut usage = MemoryUsage {
+      node_bytes: mem::size_of::<Node>(),
+      ..MemoryUsage::default()
+    };
+    self.root.add_memory_usage(&mut usage);
+    usage
+  }
+
+  pub fn reverse_index(&self) -> Option<&HashMap<String, Vec<ReverseEntry>>> {
+    self.reverse_index.as_ref()
+  }
+
+  // returns the readings of the candidate in the order of the reading.
+  // the tree is searched if the reverse index is not built
+  pub fn look_up_readings(&self, candidate: &str) -> Vec<ReverseEntry> {
+    match &self.reverse_index {
+      Some(index) => index.get(candidate).cloned().unwrap_or_default(),
+      None => self.collect_reverse_index().remove(candidate).unwrap_or_default(),
+    }
+  }
+
+  // calls f w/ the section, the key and the value of each entry of the subtree
+  // such as かえr and /帰/[る/帰/]/. the key is the readings followed by the accompanying kana
+  fn visit_entries(node: &Node, key: &mut String, f: &mut dyn FnMut(Section, &str, &str)) {
+    let mut value = String::new();
+    for (acc_kana, kanjis) in &node.kanjis {
+      if kanjis.is_empty() {
+        continue;
+      }
+      value.clear();
+      value.push('/');
+      for kanji in kanjis {
+        let _ = write!(value, "{}/", kanji);
+      }
+
+      match acc_kana {
+        Some(acc_kana) => {
+          // append okuri blocks of the accompanying kana
+          let mut okuri_blocks: Vec<_> = node.okuri_kanjis.iter()
+            .filter(|((x, _), xs)| x == acc_kana && !xs.is_empty())
+            .collect();
+          okuri_blocks.sort_by_key(|((_, okuri), _)| okuri);
+
+          for ((_, okuri), xs) in okuri_blocks {
+            let _ = write!(value, "[{}/", okuri);
+            for x in xs {
+              let _ = write!(value, "{}/", x);
+            }
+            value.push_str("]/");
+          }
+          key.push(*acc_kana);
+          f(Section::OkuriAri, key, &value);
+          key.pop();
+        },
+        None => f(Section::OkuriNasi, key, &value),
+      }
+    }
+    for (c, child) in &node.children {
+      key.push(*c);
+      Dict::visit_entries(child, key, f);
+      key.pop();
+    }
+  }
+
+  // calls f w/ the entries in the order of the tree
+  pub fn for_each_entry(&self, f: &mut dyn FnMut(Section, &str, &str)) {
+    Dict::visit_entries(&self.root, &mut String::new(), f);
+  }
+
+  // returns the dictionary in the SKK-JISYO format
+  pub fn to_lines(&self) -> Vec<String> {
+    let mut okuri_ari_lines = vec![];
+    let mut okuri_nasi_lines = vec![];
+    self.for_each_entry(&mut |section, key, value| {
+      let line = format!("{} {}", key, value);
+      match section {
+        Section::OkuriAri => okuri_ari_lines.push(line),
+        _ => okuri_nasi_lines.push(line),
+      }
+    });
+    okuri_ari_lines.sort();
+    okuri_nasi_lines.sort();
+
+    let mut lines = vec![OKURI_ARI_MARKER.to_string()];
+    lines.append(&mut okuri_ari_lines);
+    lines.push(OKURI_NASI_MARKER.to_string());
+    lines.append(&mut okuri_nasi_lines);
+    lines
+  }
+
+  pub fn look_up(&self, readings: &Vec<char>, acc_kana: &Option<char>) -> Option<Vec<&str>> {
+    let kanjis = self.look_up_with_annotations(readings, acc_kana)?;
+    Some(kanjis.iter().map(|x| x.kanji.as_str()).collect())
+  }
+
+  // looks up w/ the okuri kana as well such as し of 悔し.
+  // kanjis registered for exactly the okuri kana come first
+  pub fn look_up_okuri_strict(
+    &self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+  ) -> Option<Vec<Candidate>> {
+    let node = self.find_node(readings)?;
+    let kanjis = node.kanjis.get(acc_kana)?;
+
+    let mut res = match acc_kana {
+      Some(acc_kana) => {
+        node.okuri_kanjis.get(&(*acc_kana, okuri.to_owned()))
+          .cloned()
+          .unwrap_or_default()
+      },
+      None => vec![],
+    };
+    for kanji in kanjis {
+      Candidate::add_unique(&mut res, kanji);
+    }
+    Some(res)
+  }
+
+  pub fn look_up_with_annotations(
+    &self,
+    readings: &Vec<char>,
+    acc_kana: &Option<char>,
+  ) -> Option<&Vec<Candidate>> {
     let mut node = &self.root;
 
     for c in readings {
@@ -151,43 +854,221 @@ impl Dict {
     node.kanjis.get(acc_kana)
   }
   
-  pub fn build(lines: &Vec<String>) -> Result<Dict> {
-    let mut dict = Dict::new();
+  // returns up to limit okuri-nasi readings that start with the prefix.
+  // shorter readings come first and the prefix itself is not included
+  pub fn complete(&self, prefix: &[char], limit: usize) -> Vec<String> {
+    self.complete_where(prefix, limit, |_| true)
+  }
+
+  // same as complete but skips the readings whose kanjis don't satisfy is_completion
+  pub fn complete_where(
+    &self,
+    prefix: &[char],
+    limit: usize,
+    is_completion: impl Fn(&[Candidate]) -> bool,
+  ) -> Vec<String> {
+    let mut readings = vec![];
+    let mut node = &self.root;
 
+    for c in prefix {
+      match node.children.get(c) {
+        None => {
+          return readings;
+        },
+        Some(x) => {
+          node = x;
+        },
+      };
+    }
+
+    let mut queue = VecDeque::new();
+    queue.push_back((node, prefix.iter().collect::<String>()));
+
+    while let Some((node, reading)) = queue.pop_front() {
+      if readings.len() == limit {
+        break;
+      }
+      if reading.chars().count() > prefix.len()
+        && node.kanjis.get(&None).is_some_and(|x| !x.is_empty() && is_completion(x)) {
+        readings.push(reading.clone());
+      }
+
+      for (c, child) in &node.children {
+        queue.push_back((child, format!("{}{}", reading, c)));
+      }
+    }
+    readings
+  }
+
+  // malformed lines are skipped and reported as the warnings
+  pub fn build(lines: &[String]) -> (Dict, Vec<ParseWarning>) {
+    let mut builder = DictBuilder::new();
     for line in lines {
-      dict.add_dict_file_line(line)?;
+      builder.add_line(line);
     }
-    Ok(dict)
+    builder.finish()
+  }
+
+  // same as build but parses the lines while reading them
+  // so that the whole file is never held in memory.
+  // the encoding is detected from the first lines.
+  // on_progress is called w/ the # of read lines from time to time
+  pub fn build_from_reader<T: Read>(
+    reader: &mut BufReader<T>,
+    on_progress: &mut dyn FnMut(usize),
+  ) -> Result<(Dict, Vec<ParseWarning>)> {
+    let num_threads = thread::available_parallelism().map_or(1, |x| x.get());
+    Dict::build_from_reader_in_threads(reader, num_threads, on_progress)
+  }
+
+  // the lines are parsed in the threads sharded by the first char of the reading.
+  // each thread builds the subtrees of its own chars and they are merged w/o conflicts.
+  // the lines of a reading are parsed in the order of the file by the same thread
+  fn build_from_reader_in_threads<T: Read>(
+    reader: &mut BufReader<T>,
+    num_threads: usize,
+    on_progress: &mut dyn FnMut(usize),
+  ) -> Result<(Dict, Vec<ParseWarning>)> {
+    let mut sample = vec![];
+    reader.by_ref().take(encoding::SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    // not to cut a multibyte char in the middle
+    reader.read_until(b'\n', &mut sample)?;
+    // threads are not worth spawning for small files such as user dictionaries
+    let is_small = reader.fill_buf()?.is_empty();
+
+    let (encoding, bom_len) = DictEncoding::detect(&sample);
+    let mut sample = Cursor::new(sample);
+    sample.set_position(bom_len as u64);
+    let mut reader = sample.chain(reader);
+    let mut decode_warnings = vec![];
+
+    let (mut dict, mut warnings) = if num_threads == 1 || is_small {
+      let mut builder = DictBuilder::new();
+      Dict::read_entries(&mut reader, encoding, on_progress, &mut decode_warnings, &mut |line_num, section, line| {
+        builder.add_entry(line_num, section, &line);
+      })?;
+      builder.finish()
+    } else {
+      Dict::build_in_threads(&mut reader, encoding, num_threads, on_progress, &mut decode_warnings)?
+    };
+
+    warnings.append(&mut decode_warnings);
+    warnings.sort_by_key(|x| x.line_num);
+    dict.set_encoding(encoding);
+    Ok((dict, warnings))
   }
 
-  pub fn reader_to_lines<T: Read>(reader: &mut BufReader<T>) -> Vec<String> {
-    let mut lines = vec![];
-
-    while {
-      let mut buf = Vec::<u8>::new();
-      match reader.read_until(0x0a as u8, &mut buf) {
-        Ok(res) => {
-          if res == 0 {
-            false
-          } else {
-            let res = EUC_JP.decode(&buf);
-            let line = res.0.trim_end_matches("\n");
-            lines.push(line.to_owned());
-            true
+  // calls f w/ the line number, the section and each entry line read from the reader.
+  // lines that cannot be decoded are reported as the warnings
+  fn read_entries(
+    reader: &mut impl BufRead,
+    encoding: DictEncoding,
+    on_progress: &mut dyn FnMut(usize),
+    warnings: &mut Vec<ParseWarning>,
+    f: &mut dyn FnMut(usize, Section, String),
+  ) -> Result<()> {
+    let mut section = Section::Unknown;
+    let mut line = vec![];
+    let mut num_lines: usize = 0;
+
+    loop {
+      line.clear();
+      if reader.read_until(b'\n', &mut line)? == 0 {
+        break;
+      }
+      if num_lines.is_multiple_of(PROGRESS_INTERVAL) {
+        on_progress(num_lines);
+      }
+      num_lines += 1;
+
+      // '\n' never appears in a multibyte char of the encodings
+      let Some(line) = encoding.decode_strict(&line) else {
+        warnings.push(ParseWarning {
+          line_num: num_lines,
+          reason: format!("Line cannot be decoded as {:?}", encoding),
+        });
+        continue;
+      };
+      if let Some(x) = Section::from_marker(Dict::normalize_line(&line)) {
+        section = x;
+        continue;
+      }
+      f(num_lines, section, line);
+    }
+    on_progress(num_lines);
+    Ok(())
+  }
+
+  fn build_in_threads(
+    reader: &mut impl BufRead,
+    encoding: DictEncoding,
+    num_threads: usize,
+    on_progress: &mut dyn FnMut(usize),
+    decode_warnings: &mut Vec<ParseWarning>,
+  ) -> Result<(Dict, Vec<ParseWarning>)> {
+    thread::scope(|scope| {
+      let mut senders = vec![];
+      let mut workers = vec![];
+
+      for _ in 0..num_threads {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<(usize, Section, String)>>(MAX_PENDING_BATCHES);
+        senders.push(sender);
+        workers.push(scope.spawn(move || {
+          let mut builder = DictBuilder::new();
+          for batch in receiver {
+            for (line_num, section, line) in batch {
+              builder.add_entry(line_num, section, &line);
+            }
           }
-        },
-        Err(e) => {
-          println!("Failed to read: {:?}", e);
-          true
+          builder.finish()
+        }));
+      }
+
+      let mut batches = vec![vec![]; num_threads];
+      let read_result = Dict::read_entries(reader, encoding, on_progress, decode_warnings, &mut |line_num, section, line| {
+        let shard = Dict::normalize_line(&line).chars().next().map_or(0, |c| c as usize % num_threads);
+        batches[shard].push((line_num, section, line));
+
+        // a send fails only if the thread has panicked, which is reported on join
+        if batches[shard].len() == BATCH_LEN {
+          let _ = senders[shard].send(mem::take(&mut batches[shard]));
+        }
+      });
+      // the threads stop once the senders are dropped. the rest is discarded on error
+      if read_result.is_ok() {
+        for (sender, batch) in senders.iter().zip(batches) {
+          let _ = sender.send(batch);
         }
       }
-    } {}
+      drop(senders);
 
-    return lines;
+      // all the threads are joined before returning an error
+      // so that the scope doesn't panic for the ones that have panicked
+      let mut results = vec![];
+      let mut panic_message = None;
+      for worker in workers {
+        match worker.join() {
+          Ok(result) => results.push(result),
+          Err(e) => panic_message = Some(error::panic_message(&*e).to_string()),
+        }
+      }
+      if let Some(message) = panic_message {
+        return Err(Error::other(format!("Failed to parse lines: {}", message)));
+      }
+      read_result?;
+
+      let mut dict = Dict::new();
+      let mut warnings = vec![];
+      for (x, mut xs) in results {
+        dict.root.append(x.root);
+        warnings.append(&mut xs);
+      }
+      Ok((dict, warnings))
+    })
   }
 
   pub fn serialize_to_file(&self, path: &PathBuf) -> Result<()> {
-    match bincode::serialize(self) {
+    match bincode::serialize(&(SER_MAGIC, SER_VERSION, PARSER_VERSION, self)) {
       Ok(ser_dict) => {
         let file = File::create(path)?;
         let mut enc = GzEncoder::new(file, Compression::best());
@@ -197,8 +1078,7 @@ impl Dict {
         Ok(())
       },
       Err(e) => {
-        Err(Error::new(
-          ErrorKind::Other,
+        Err(Error::other(
           format!("Failed to serializing dict: {:?}", e)
         ))
       },
@@ -213,24 +1093,46 @@ impl Dict {
     let mut dec = GzDecoder::new(Vec::new());
     dec.write_all(&comp_buf)?;
     let decomp_buf = dec.finish()?;
-    
-    bincode::deserialize(&decomp_buf).map_err(|e| Error::new(
+
+    let to_error = |e| Error::new(
       ErrorKind::InvalidData,
       format!("Failed to deserialize dict: {:?}", e)
-    ))
+    );
+
+    // check the header first not to misinterpret a file of another version
+    let (magic, version, parser_version): ([u8; 4], u32, u32) =
+      bincode::deserialize(&decomp_buf).map_err(to_error)?;
+    if magic != SER_MAGIC || version != SER_VERSION {
+      return Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("Unsupported serialized dict version: {}", version)
+      ));
+    }
+    if parser_version != PARSER_VERSION {
+      return Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("Serialized dict was built by another parser: {}", parser_version)
+      ));
+    }
+    let (_, _, _, dict): ([u8; 4], u32, u32, Dict) =
+      bincode::deserialize(&decomp_buf).map_err(to_error)?;
+    Ok(dict)
   }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
   use super::*;
 
+  use crate::system_dict::SystemDict;
+
   #[test]
   pub fn test_parse_line() {
     let line = "わるs /碍/";
     let dict = Dict::new(); 
 
-    let maybe_res = dict.parse_line(line);
+    let maybe_res = dict.parse_line(line).unwrap();
     match maybe_res {
       None => assert!(false),
       Some(res) => {
@@ -246,7 +1148,7 @@ mod tests {
 
     let line = "Cyrillic /А/Б/В/Г/Д/Е/Ё/Ж/З/И/Й/К/Л/М/Н/О/П/Р/С/Т/У/Ф/Х/Ц/Ч/Ш/Щ/Ъ/Ы/Ь/Э/Ю/Я/";
     let dict = Dict::new(); 
-    let maybe_res = dict.parse_line(line);
+    let maybe_res = dict.parse_line(line).unwrap();
     match maybe_res {
       None => assert!(false),
       Some(res) => {
@@ -254,7 +1156,7 @@ mod tests {
         let exp: Vec<String> = "Cyrillic".chars().map(|c| c.to_string()).collect(); 
         assert_eq!(act, exp);
 
-        let act: Vec<String> = res.kanjis;
+        let act: Vec<String> = res.kanjis.into_iter().map(|x| x.kanji).collect();
         let exp: Vec<String> = "А/Б/В/Г/Д/Е/Ё/Ж/З/И/Й/К/Л/М/Н/О/П/Р/С/Т/У/Ф/Х/Ц/Ч/Ш/Щ/Ъ/Ы/Ь/Э/Ю/Я".split('/').map(|c| c.to_string()).collect(); 
         assert_eq!(act, exp);
       },
@@ -487,6 +1389,344 @@ mod tests {
     }
   }
 
+  #[test]
+  pub fn test_section_markers() {
+    let lines: Vec<String> = vec![
+      ";; -*- mode: fundamental; coding: euc-jp -*-",
+      ";; okuri-ari entries.",
+      "わるs /碍/",
+      "おもu /思/",
+      ";; okuri-nasi entries.",
+      "Cyrillic /А/Б/В/",
+      "request /リクエスト/",
+      "あいて /陵缄/",
+    ].into_iter().map(|x| x.to_string()).collect();
+    let (dict, _) = Dict::build(&lines);
+
+    let readings: Vec<char> = "Cyrillic".chars().collect();
+    assert_eq!(dict.look_up(&readings, &None).unwrap(), vec!["А", "Б", "В"]);
+    let readings: Vec<char> = "Cyrilli".chars().collect();
+    if dict.look_up(&readings, &Some('c')).is_some() {
+      assert!(false);
+    }
+    let readings: Vec<char> = "request".chars().collect();
+    assert_eq!(dict.look_up(&readings, &None).unwrap(), vec!["リクエスト"]);
+    assert_eq!(dict.look_up(&vec!['わ', 'る'], &Some('s')).unwrap(), vec!["碍"]);
+    assert_eq!(dict.look_up(&vec!['お', 'も'], &Some('u')).unwrap(), vec!["思"]);
+    assert_eq!(dict.look_up(&vec!['あ', 'い', 'て'], &None).unwrap(), vec!["陵缄"]);
+
+    // ascii readings should be taken verbatim even w/o the markers
+    let mut dict = Dict::new();
+    dict.add_dict_file_line("Cyrillic /А/").unwrap();
+    let readings: Vec<char> = "Cyrillic".chars().collect();
+    assert_eq!(dict.look_up(&readings, &None).unwrap(), vec!["А"]);
+  }
+
+  #[test]
+  pub fn test_okuri_blocks() {
+    let line = "くやs /悔/[し/悔/]/";
+    let dict = Dict::new();
+    let res = dict.parse_line(line).unwrap().unwrap();
+    assert_eq!(res.kanjis, vec!["悔"]);
+    assert_eq!(res.okuri_blocks, vec![("し".to_string(), vec![Candidate::new("悔")])]);
+
+    let lines: Vec<String> = vec![
+      ";; okuri-ari entries.",
+      "かえr /帰/返/換/[る/帰/返/]/[り/帰/]/[れ/換;かえる/]/",
+    ].into_iter().map(|x| x.to_string()).collect();
+    let (mut dict, _) = Dict::build(&lines);
+    let readings = vec!['か', 'え'];
+
+    // block markers should not be candidates
+    assert_eq!(dict.look_up(&readings, &Some('r')).unwrap(), vec!["帰", "返", "換"]);
+
+    let kanjis = dict.look_up_okuri_strict(&readings, &Some('r'), "れ").unwrap();
+    assert_eq!(kanjis, vec!["換", "帰", "返"]);
+    assert_eq!(kanjis[0].annotation.as_deref(), Some("かえる"));
+    let kanjis = dict.look_up_okuri_strict(&readings, &Some('r'), "り").unwrap();
+    assert_eq!(kanjis, vec!["帰", "返", "換"]);
+    let kanjis = dict.look_up_okuri_strict(&readings, &Some('r'), "ら").unwrap();
+    assert_eq!(kanjis, vec!["帰", "返", "換"]);
+    assert!(dict.look_up_okuri_strict(&readings, &Some('s'), "す").is_none());
+
+    // blocks should be written back. the annotation in the block is taken over
+    assert_eq!(
+      dict.to_lines()[1],
+      "かえr /帰/返/換;かえる/[り/帰/]/[る/帰/返/]/[れ/換;かえる/]/",
+    );
+
+    let kanjis = dict.look_up_okuri_strict(&readings, &Some('r'), "れ").unwrap();
+    assert_eq!(kanjis, vec!["換", "帰", "返"]);
+
+    // the kanji should be moved within the okuri block as well
+    dict.move_to_front_okuri(&readings, &Some('r'), "る", "返");
+    dict.move_to_front_okuri(&readings, &Some('r'), "ら", "換");
+    assert_eq!(dict.look_up(&readings, &Some('r')).unwrap(), vec!["換", "返", "帰"]);
+    assert_eq!(dict.look_up_okuri_strict(&readings, &Some('r'), "る").unwrap(), vec!["返", "帰", "換"]);
+    assert_eq!(dict.look_up_okuri_strict(&readings, &Some('r'), "ら").unwrap(), vec!["換", "返", "帰"]);
+
+    // removed kanjis should be gone from the okuri blocks
+    assert!(dict.remove(&readings, &Some('r'), "換"));
+    assert_eq!(
+      dict.to_lines()[1],
+      "かえr /返/帰/[り/帰/]/[る/返/帰/]/",
+    );
+  }
+
+  #[test]
+  pub fn test_reverse_index() {
+    let lines: Vec<String> = vec![
+      ";; okuri-ari entries.",
+      "かえr /帰/返/[る/帰/返/]/",
+      "もどr /戻/返/",
+      ";; okuri-nasi entries.",
+      "きかん /帰還/機関/",
+      "へんかん /返還/(concat \"変換\")/",
+      "#がつ /#1月/",
+    ].into_iter().map(|x| x.to_string()).collect();
+    let (mut dict, _) = Dict::build(&lines);
+
+    let entry = |reading: &str, acc_kana: Option<char>, okuri: &str| ReverseEntry {
+      reading: reading.to_string(),
+      acc_kana,
+      okuri: okuri.to_string(),
+    };
+    let check = |dict: &Dict| {
+      assert_eq!(
+        dict.look_up_readings("返"),
+        vec![entry("かえ", Some('r'), "る"), entry("もど", Some('r'), "")],
+      );
+      assert_eq!(dict.look_up_readings("機関"), vec![entry("きかん", None, "")]);
+      // lisp candidates should be found as rendered
+      assert_eq!(dict.look_up_readings("変換"), vec![entry("へんかん", None, "")]);
+      assert!(dict.look_up_readings("#1月").is_empty());
+      assert!(dict.look_up_readings("漢字").is_empty());
+    };
+
+    // should be the same w/ and w/o the index
+    check(&dict);
+    dict.build_reverse_index();
+    assert!(dict.has_reverse_index());
+    check(&dict);
+
+    // the index should be dropped once modified
+    dict.add(&['か', 'ん'], &None, "缶");
+    assert!(!dict.has_reverse_index());
+    assert_eq!(dict.look_up_readings("缶"), vec![entry("かん", None, "")]);
+  }
+
+  #[test]
+  pub fn test_annotations() {
+    let line = "りょう /陵;みささぎ/稜;かど/両/";
+    let mut dict = Dict::new();
+    dict.add_dict_file_line(line).unwrap();
+
+    let readings = vec!['り', 'ょ', 'う'];
+    assert_eq!(dict.look_up(&readings, &None).unwrap(), vec!["陵", "稜", "両"]);
+
+    let kanjis = dict.look_up_with_annotations(&readings, &None).unwrap();
+    assert_eq!(kanjis[0].annotation.as_deref(), Some("みささぎ"));
+    assert_eq!(kanjis[1].annotation.as_deref(), Some("かど"));
+    assert_eq!(kanjis[2].annotation, None);
+
+    // annotations should survive moving to front and writing lines
+    dict.move_to_front(&readings, &None, "稜");
+    assert_eq!(dict.to_lines()[2], "りょう /稜;かど/陵;みささぎ/両/");
+
+    // an annotation missing in a higher priority dictionary should be taken over
+    let mut user_dict = Dict::new();
+    user_dict.add_dict_file_line("りょう /両/陵/").unwrap();
+    let dicts = vec![SystemDict::Tree(user_dict), SystemDict::Tree(dict)];
+    let kanjis = SystemDict::look_up_in_order(&dicts, &readings, &None, "").unwrap();
+    assert_eq!(kanjis, vec!["両", "陵", "稜"]);
+    assert_eq!(kanjis[1].annotation.as_deref(), Some("みささぎ"));
+  }
+
+  #[test]
+  pub fn test_serialization() {
+    let path = std::env::temp_dir()
+      .join(format!("minskk-{}-dict.ser.gz", std::process::id()));
+    let mut dict = Dict::new();
+    dict.add_dict_file_line("りょう /陵;みささぎ/両/").unwrap();
+    dict.set_encoding(DictEncoding::Utf8);
+    dict.build_reverse_index();
+    dict.serialize_to_file(&path).unwrap();
+
+    let dict = Dict::deserialize_from_file(&path).unwrap();
+    let kanjis = dict.look_up_with_annotations(&vec!['り', 'ょ', 'う'], &None).unwrap();
+    assert_eq!(kanjis, &vec![Candidate::parse("陵;みささぎ"), Candidate::new("両")]);
+    assert_eq!(dict.encoding(), DictEncoding::Utf8);
+    // the reverse index should be cached along w/ the tree
+    assert!(dict.has_reverse_index());
+
+    // files w/o the header or built by another parser should be rejected
+    let headers = [
+      bincode::serialize(&dict).unwrap(),
+      bincode::serialize(&(SER_MAGIC, SER_VERSION, PARSER_VERSION + 1, &dict)).unwrap(),
+    ];
+    for bytes in headers {
+      let file = File::create(&path).unwrap();
+      let mut enc = GzEncoder::new(file, Compression::best());
+      enc.write_all(&bytes).unwrap();
+      enc.finish().unwrap();
+      assert!(Dict::deserialize_from_file(&path).is_err());
+    }
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_build_skips_malformed_lines() {
+    let lines: Vec<String> = vec![
+      "\u{feff};; okuri-ari entries.\r",
+      "かえr /帰/\r",
+      "malformed",
+      ";; okuri-nasi entries.\r",
+      "",
+      "かん\t/缶/管/\r",
+      "き 木/",
+      " /空/",
+      "じ /字/",
+    ].into_iter().map(|x| x.to_string()).collect();
+    let (dict, warnings) = Dict::build(&lines);
+
+    // CRLF, tabs and BOM should be accepted
+    assert_eq!(dict.look_up(&vec!['か', 'え'], &Some('r')).unwrap(), vec!["帰"]);
+    assert_eq!(dict.look_up(&vec!['か', 'ん'], &None).unwrap(), vec!["缶", "管"]);
+    assert_eq!(dict.look_up(&vec!['じ'], &None).unwrap(), vec!["字"]);
+
+    // lines after malformed ones should still be added
+    let line_nums: Vec<usize> = warnings.iter().map(|x| x.line_num).collect();
+    assert_eq!(line_nums, vec![3, 7, 8]);
+    assert!(dict.look_up(&vec!['き'], &None).is_none());
+  }
+
+  #[test]
+  pub fn test_build_with_progress() {
+    let text: String = (0..PROGRESS_INTERVAL + 1)
+      .map(|i| format!("{} /x/\n", i))
+      .collect();
+    let mut progress = vec![];
+    let mut reader = BufReader::new(text.as_bytes());
+    let (dict, _) = Dict::build_from_reader(&mut reader, &mut |x| progress.push(x)).unwrap();
+    assert_eq!(progress, vec![0, PROGRESS_INTERVAL, PROGRESS_INTERVAL + 1]);
+    assert_eq!(dict.look_up(&vec!['1', '0'], &None).unwrap(), vec!["x"]);
+  }
+
+  #[test]
+  pub fn test_build_from_reader_with_bom() {
+    // the BOM should be skipped and the header should be taken as a comment
+    let text = "\u{feff};; -*- coding: utf-8 -*-\nかんじ /漢字/\n";
+    let mut reader = BufReader::new(text.as_bytes());
+    let (dict, warnings) = Dict::build_from_reader(&mut reader, &mut |_| {}).unwrap();
+    assert_eq!(dict.encoding(), DictEncoding::Utf8);
+    assert_eq!(dict.look_up(&vec!['か', 'ん', 'じ'], &None).unwrap(), vec!["漢字"]);
+    assert!(warnings.is_empty());
+  }
+
+  #[test]
+  pub fn test_build_from_reader() {
+    // lines beyond the sample for the encoding should be parsed as well
+    let mut text = String::from(";; okuri-nasi entries.\n");
+    while text.len() < encoding::SAMPLE_SIZE {
+      text.push_str("かんじ /漢字/\n");
+    }
+    text.push_str("malformed\nかん /缶/\n");
+    let (bytes, _, _) = encoding_rs::EUC_JP.encode(&text);
+
+    let mut progress = vec![];
+    let mut reader = BufReader::with_capacity(16, bytes.as_ref());
+    let (dict, warnings) = Dict::build_from_reader(&mut reader, &mut |x| progress.push(x)).unwrap();
+    let num_lines = text.lines().count();
+
+    assert_eq!(dict.encoding(), DictEncoding::EucJp);
+    assert_eq!(dict.look_up(&vec!['か', 'ん'], &None).unwrap(), vec!["缶"]);
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].line_num, num_lines - 1);
+    assert_eq!(progress.first(), Some(&0));
+    assert_eq!(progress.last(), Some(&num_lines));
+  }
+
+  #[test]
+  pub fn test_build_in_threads() {
+    let mut lines = vec![
+      "ぬ /無/".to_string(),
+      "Cyrillic /А/".to_string(),
+      ";; okuri-ari entries.".to_string(),
+    ];
+    for i in 0..BATCH_LEN * 3 {
+      let c = char::from_u32('あ' as u32 + (i % 80) as u32).unwrap();
+      lines.push(format!("{}{}k /{}/[く/{}/]/", c, i, i, i));
+    }
+    lines.push(";; okuri-nasi entries.".to_string());
+    for i in 0..BATCH_LEN * 3 {
+      let c = char::from_u32('あ' as u32 + (i % 80) as u32).unwrap();
+      lines.push(format!("{}{} /{}/", c, i % 100, i));
+      if i % 500 == 0 {
+        lines.push(format!("{}malformed", c));
+      }
+    }
+    let text = lines.join("\n") + "\n";
+
+    // should be the same as the one built by a thread.
+    // candidates of the same reading should keep the order of the lines
+    let (expected, expected_warnings) = Dict::build(&lines);
+    let mut reader = BufReader::new(text.as_bytes());
+    let (dict, warnings) = Dict::build_from_reader_in_threads(&mut reader, 4, &mut |_| {}).unwrap();
+
+    assert_eq!(dict.to_lines(), expected.to_lines());
+    assert_eq!(warnings, expected_warnings);
+    assert_eq!(dict.memory_usage(), expected.memory_usage());
+    assert_eq!(dict.look_up(&vec!['ぬ'], &None).unwrap(), vec!["無"]);
+  }
+
+  #[test]
+  pub fn test_read_error_in_threads() {
+    // fails after the sample so that the lines are parsed in the threads
+    struct FailingReader(usize);
+    impl Read for FailingReader {
+      fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.0 > encoding::SAMPLE_SIZE * 2 {
+          return Err(Error::other("broken"));
+        }
+        let line = b"\xa4\xab\xa4\xf3 /\xb4\xc1/\n";
+        let len = usize::min(buf.len(), line.len());
+        buf[..len].copy_from_slice(&line[..len]);
+        self.0 += len;
+        Ok(len)
+      }
+    }
+    let mut reader = BufReader::new(FailingReader(0));
+    let e = Dict::build_from_reader_in_threads(&mut reader, 4, &mut |_| {}).unwrap_err();
+    assert_eq!(e.to_string(), "broken");
+  }
+
+  #[test]
+  pub fn test_complete() {
+    let lines = vec![
+      "かんじ /漢字/",
+      "かんじょう /勘定/感情/",
+      "かんじゃ /患者/",
+      "かん /缶/",
+      "かk /書/",
+      "かんがe /考/",
+      "き /木/",
+    ];
+    let mut dict = Dict::new();
+    for line in lines {
+      dict.add_dict_file_line(line).unwrap();
+    }
+
+    assert_eq!(
+      dict.complete(&['か'], 10),
+      vec!["かん", "かんじ", "かんじゃ", "かんじょう"],
+    );
+    assert_eq!(dict.complete(&['か', 'ん', 'じ'], 10), vec!["かんじゃ", "かんじょう"]);
+    assert_eq!(dict.complete(&['か'], 2), vec!["かん", "かんじ"]);
+    assert!(dict.complete(&['か', 'ん', 'じ', 'ょ', 'う'], 10).is_empty());
+    assert!(dict.complete(&['さ'], 10).is_empty());
+  }
+
   #[ignore]
   #[test]
   pub fn trest_build() {
@@ -495,13 +1735,13 @@ mod tests {
 
     let file = File::open(&dict_file).unwrap();
     let mut reader = BufReader::new(file);
-    let lines = Dict::reader_to_lines(&mut reader);
-    
+
     let start = std::time::Instant::now();
-    let dict = Dict::build(&lines).unwrap();
+    let (dict, _) = Dict::build_from_reader(&mut reader, &mut |_| {}).unwrap();
     let duration = start.elapsed();
 
     println!("Took {} ms to load", duration.as_millis());
+    println!("{}", dict.memory_usage());
 
     let readings = vec!['と', 'け', 'い'];
     match dict.look_up(&readings, &None) {
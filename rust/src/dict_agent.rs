@@ -1,70 +1,262 @@
-use crate::dict::Dict;
+use crate::{
+  dict::{Candidate, Dict, ParseWarning, ReverseEntry},
+  error::{self, AgentError, ErrorCode},
+  lisp,
+  mmap_dict::MmapDict,
+  numeric,
+  sorted_dict::SortedDict,
+  system_dict::SystemDict,
+  user_dict::UserDict,
+};
 
 use flate2::read::GzDecoder;
 use libc::{c_char, size_t};
-use once_cell::sync::{Lazy, OnceCell};
+use once_cell::sync::Lazy;
 use std::{
-  ffi::CStr,
+  cell::Cell,
+  ffi::{CStr, CString},
   fs::{self, File},
-  io::{BufReader, Read},
+  io::{self, BufReader, ErrorKind, Read},
+  panic::{self, AssertUnwindSafe},
   path::{Path, PathBuf},
   ptr,
   slice,
   str::FromStr,
-  sync::Mutex,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc::{self, Sender},
+    Arc,
+    Mutex,
+    MutexGuard,
+    PoisonError,
+    RwLock,
+    RwLockReadGuard,
+    RwLockWriteGuard,
+  },
   thread,
+  time::Duration,
 };
 
-static DICT: OnceCell<Mutex<Dict>> = OnceCell::new();
-static RESULT_CACHE: Lazy<Mutex<Vec<String>>> =
-  Lazy::new(|| Mutex::new(vec![]));
+// the user dictionary is saved once no change is made for the delay
+// so that the keys are not blocked by writing the file on every commit
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+// agent used by the functions w/o a handle
+static DEFAULT_AGENT: Lazy<Arc<DictAgent>> = Lazy::new(|| Arc::new(DictAgent::new()));
+// results of the last look_up or complete w/o a handle
+static RESULT_CACHE: Lazy<Mutex<LookUpResults>> =
+  Lazy::new(|| Mutex::new(LookUpResults::default()));
+
+struct DictWarning {
+  path: PathBuf,
+  warning: ParseWarning,
+}
+
+// formats the warning as in compiler messages
+impl std::fmt::Display for DictWarning {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{}:{}: {}", self.path.display(), self.warning.line_num, self.warning.reason)
+  }
+}
+
+// candidate as in the dictionary and the string to show for it.
+// numeric_reading is the reading w/ numbers replaced by '#' if the candidate is a numeric one.
+// okuri is of the readings returned by reverse_look_up in the form given to look_up
+struct LookUpResult {
+  candidate: Candidate,
+  rendered: String,
+  numeric_reading: Option<Vec<char>>,
+  okuri: String,
+}
+
+// result set of a look_up or complete
+#[derive(Default)]
+pub struct LookUpResults {
+  results: Vec<LookUpResult>,
+}
+
+impl LookUpResults {
+  // evaluates lisp candidates. unsupported ones and duplicates after evaluation are dropped
+  fn render_all(&mut self, candidates: Vec<Candidate>) {
+    for candidate in candidates {
+      if let Some(rendered) = lisp::render(&candidate.kanji) {
+        self.push_unique(candidate, rendered, None);
+      }
+    }
+  }
+
+  // replaces #0 to #9 in the candidates of the numeric reading w/ the numbers.
+  // look_up_number is used for #4 that converts the number as a reading
+  fn render_numeric(
+    &mut self,
+    numeric_reading: &[char],
+    numbers: &[String],
+    candidates: Vec<Candidate>,
+    look_up_number: &dyn Fn(&str) -> Option<String>,
+  ) {
+    for candidate in candidates {
+      if let Some(rendered) = numeric::render(&candidate.kanji, numbers, look_up_number) {
+        self.push_unique(candidate, rendered, Some(numeric_reading.to_vec()));
+      }
+    }
+  }
+
+  fn push_unique(
+    &mut self,
+    candidate: Candidate,
+    rendered: String,
+    numeric_reading: Option<Vec<char>>,
+  ) {
+    if !self.results.iter().any(|x| x.rendered == rendered) {
+      self.results.push(LookUpResult { candidate, rendered, numeric_reading, okuri: String::new() });
+    }
+  }
+
+  fn push_reading(&mut self, entry: ReverseEntry) {
+    let okuri = match entry.acc_kana {
+      Some(acc_kana) => format!("{}{}", acc_kana, entry.okuri),
+      None => String::new(),
+    };
+    if !self.results.iter().any(|x| x.rendered == entry.reading && x.okuri == okuri) {
+      self.results.push(LookUpResult {
+        candidate: Candidate::new(&entry.reading),
+        rendered: entry.reading,
+        numeric_reading: None,
+        okuri,
+      });
+    }
+  }
+
+  // returns the reading and the candidate as in the dictionary for the string shown to the user
+  fn to_dict_entry(&self, reading: Vec<char>, rendered: &str) -> (Vec<char>, String) {
+    match self.results.iter().find(|x| x.rendered == rendered) {
+      Some(result) => (
+        result.numeric_reading.clone().unwrap_or(reading),
+        result.candidate.kanji.clone(),
+      ),
+      None => (reading, rendered.to_owned()),
+    }
+  }
+
+  fn rendered(&self) -> Vec<&str> {
+    self.results.iter().map(|x| x.rendered.as_str()).collect()
+  }
+
+  fn okuris(&self) -> Vec<&str> {
+    self.results.iter().map(|x| x.okuri.as_str()).collect()
+  }
+
+  // an empty string is returned for a result w/o annotation
+  fn annotations(&self) -> Vec<&str> {
+    self.results.iter()
+      .map(|x| x.candidate.annotation.as_deref().unwrap_or(""))
+      .collect()
+  }
+}
 
+// file to load and the paths needed to rebuild or cache the dictionary
 pub enum DictFile {
+  // mmap cache and the base path
+  Mapped(PathBuf, PathBuf),
+  // ser.gz cache of older versions, the base path and the mmap cache to write
+  SerGz(PathBuf, PathBuf, PathBuf),
+  // source file and the mmap cache to write
   Gz(PathBuf, PathBuf),
-  SerGz(PathBuf),
   Raw(PathBuf, PathBuf),
+  // uncompressed source file to look up in place and the mmap cache to write if it cannot be
+  Sorted(PathBuf, PathBuf),
   NotFound,
 }
 
 #[repr(C)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum BuildResult {
   Success = 0,
   FileNotFound = 1,
   PathMalformed= 2,
+  // the reason is queued as an error
+  Failed = 3,
 }
 
-fn read_lines_and_set_dict<T: Read>(reader: &mut BufReader<T>) {
-  let lines = Dict::reader_to_lines(reader);
-  match Dict::build(&lines) {
-    Ok(dict) => DICT.set(Mutex::<Dict>::new(dict)).unwrap(),
-    Err(e) => {
-      println!("Failed to build dictionary: {:?}", e);
-    },
-  }
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum BuildState {
+  #[default]
+  NotStarted = 0,
+  LoadingCache = 1,
+  Parsing = 2,
+  Serializing = 3,
+  Ready = 4,
+  // no dictionary could be built
+  Failed = 5,
+}
+
+// progress of the dictionaries being built in the background.
+// num_bytes_done/num_bytes are of the file of the dictionary being parsed
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct BuildProgress {
+  pub state: BuildState,
+  pub num_dicts_done: size_t,
+  pub num_dicts: size_t,
+  pub num_bytes_done: size_t,
+  pub num_bytes: size_t,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum UserDictResult {
+  Success = 0,
+  UserDictNotLoaded = 1,
+  Failed = 2,
+}
+
+// locks recovering from the poison left by a panic.
+// the panic has been reported and the data is still usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+  lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+  lock.write().unwrap_or_else(PoisonError::into_inner)
 }
 
 fn get_path_ser_gz(
   dir: &Option<&Path>,
-  file_name: &String,
+  file_name: &str,
+) -> PathBuf {
+  match dir {
+    Some(dir) => dir.join(file_name.to_owned() + ".ser.gz"),
+    None => { PathBuf::from(file_name.to_owned() + ".ser.gz") },
+  }
+}
+
+fn get_path_mmap(
+  dir: &Option<&Path>,
+  file_name: &str,
 ) -> PathBuf {
   match dir {
-    Some(dir) => dir.join(file_name.clone() + ".ser.gz"),
-    None => { PathBuf::from(file_name.clone() + ".ser.gz") },
+    Some(dir) => dir.join(file_name.to_owned() + ".mmap"),
+    None => { PathBuf::from(file_name.to_owned() + ".mmap") },
   }
 }
 
 fn get_path_gz(
   dir: &Option<&Path>,
-  file_name: &String,
+  file_name: &str,
 ) -> PathBuf {
   match dir {
-    Some(dir) => dir.join(file_name.clone() + ".gz"),
-    None => { PathBuf::from(file_name.clone() + ".gz") },
+    Some(dir) => dir.join(file_name.to_owned() + ".gz"),
+    None => { PathBuf::from(file_name.to_owned() + ".gz") },
   }
 }
 
 fn exists_as_file(path: &PathBuf) -> bool {
-  path.exists() && fs::metadata(&path).unwrap().is_file()
+  fs::metadata(path).is_ok_and(|x| x.is_file())
 }
 
 fn drop_gz_suffix_if_exists(s: String) -> String {
@@ -75,205 +267,1777 @@ fn drop_gz_suffix_if_exists(s: String) -> String {
 }
 
 // load precedence:
-// 1. ser.gz
-// 2. gz
-// 3. others
-fn get_dict_file_to_load(base_path: &PathBuf) -> DictFile {
+// 1. uncompressed source file if looked up in place
+// 2. mmap not older than the source
+// 3. ser.gz not older than the source
+// 4. gz
+// 5. others
+fn get_dict_file_to_load(base_path: &PathBuf, lazy_load: bool) -> DictFile {
+  if lazy_load {
+    // a gzipped file cannot be looked up in place
+    if let DictFile::Raw(path_raw, path_mmap) = get_dict_file(base_path, false) {
+      return DictFile::Sorted(path_raw, path_mmap);
+    }
+  }
+  get_dict_file(base_path, true)
+}
+
+// a cache is stale if the source has been modified after the cache was written.
+// it is used if the source is gone or the times are not available
+fn is_up_to_date(path_cache: &PathBuf, path_source: Option<&PathBuf>) -> bool {
+  if !exists_as_file(path_cache) {
+    return false;
+  }
+  let modified = |path: &PathBuf| fs::metadata(path).and_then(|x| x.modified()).ok();
+  match (modified(path_cache), path_source.and_then(modified)) {
+    (Some(cache), Some(source)) => cache >= source,
+    _ => true,
+  }
+}
+
+fn get_dict_file(base_path: &PathBuf, use_cache: bool) -> DictFile {
   match &base_path.file_name() {
     None => DictFile::NotFound,
     Some(file_name) => {
       let file_name = drop_gz_suffix_if_exists(
-        file_name.to_str().unwrap().to_string()
+        file_name.to_string_lossy().into_owned()
       );
       let dir = base_path.parent();
 
-      // if ser.gz exist, should load it
+      // should load .gz if exists. otherwise base_path can be a raw file
+      let path_gz = get_path_gz(&dir, &file_name);
+      let is_gz = exists_as_file(&path_gz);
+      let path_source = if is_gz {
+        Some(path_gz)
+      } else if exists_as_file(base_path) {
+        Some(base_path.clone())
+      } else {
+        None
+      };
+
+      // if an up-to-date cache exists, should load it
+      let path_mmap = get_path_mmap(&dir, &file_name);
       let path_ser_gz = get_path_ser_gz(&dir, &file_name); 
-      if exists_as_file(&path_ser_gz) {
-        DictFile::SerGz(path_ser_gz)
+      if use_cache && is_up_to_date(&path_mmap, path_source.as_ref()) {
+        DictFile::Mapped(path_mmap, base_path.clone())
+
+      } else if use_cache && is_up_to_date(&path_ser_gz, path_source.as_ref()) {
+        DictFile::SerGz(path_ser_gz, base_path.clone(), path_mmap)
 
       } else {
-        // otherwise, should load .gz if exists
-        let path_gz = get_path_gz(&dir, &file_name);
-        if exists_as_file(&path_gz) {
-          DictFile::Gz(path_gz, path_ser_gz)
-
-        } else {
-          // if both don't exist, base_path can be a raw file
-          if exists_as_file(base_path) {
-            DictFile::Raw(base_path.clone(), path_ser_gz)
-
-          } else {
-            DictFile::NotFound
-          }
+        match path_source {
+          Some(path) if is_gz => DictFile::Gz(path, path_mmap),
+          Some(path) => DictFile::Raw(path, path_mmap),
+          None => DictFile::NotFound,
         }
       }
     }
   }
 }
 
-fn gen_ser_gz(path_ser_gz: &PathBuf) {
-  match &DICT.get() {
-    Some(dict) => {
-      let dict = dict.lock().unwrap();
-      if let Err(e) = dict.serialize_to_file(&path_ser_gz) {
-        println!("{:?}", e);
-      }
-    },
-    None => println!("should not be visited. check code (dict-agent 2)"),
-  };
+// counts the bytes read for the progress
+struct CountingReader<'a, T: Read> {
+  inner: T,
+  num_bytes_read: &'a Cell<usize>,
 }
 
-#[no_mangle]
-pub extern "C" fn build_from_file(dict_file: &DictFile) {
-  match dict_file {
-    DictFile::SerGz(path_ser_gz) => {
-      match Dict::deserialize_from_file(&path_ser_gz) {
-        Ok(dict) => {
-          DICT.set(Mutex::<Dict>::new(dict)).unwrap();
-        },  
-        Err(e) => {
-          println!("{:?}", e);
-        },
-      }
-    },
-    DictFile::Gz(path_gz, path_ser_gz) => {
-      let file = File::open(&path_gz).unwrap();
-      let file = GzDecoder::new(file);
-      let mut reader = BufReader::new(file);
-      read_lines_and_set_dict(&mut reader);
-      gen_ser_gz(&path_ser_gz);
-    },
-    DictFile::Raw(path_raw, path_ser_gz) => {
-      let file = File::open(&path_raw).unwrap();
-      let mut reader = BufReader::new(file);
-      read_lines_and_set_dict(&mut reader);
-      gen_ser_gz(&path_ser_gz);
-    },
-    DictFile::NotFound => {
-      println!("should not be visited. check code (dict_agent 1)");
-    }
+impl<T: Read> Read for CountingReader<'_, T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.num_bytes_read.set(self.num_bytes_read.get() + n);
+    Ok(n)
   }
 }
 
-#[no_mangle]
-pub extern "C" fn build(
-  base_dict_file_path: *const c_char,
-) -> BuildResult {
-  let base_dict_file_path = unsafe {
-    CStr::from_ptr(base_dict_file_path).to_str().unwrap()
-  }.to_string();
-
-  let base_dict_file_path =
-    shellexpand::tilde(&base_dict_file_path);
-  
-  match PathBuf::from_str(&base_dict_file_path) {
-    Ok(base_dict_file_path) => {
-      match get_dict_file_to_load(&base_dict_file_path) {
-        DictFile::NotFound => {
-          BuildResult::FileNotFound
-        },
-        dict_file => {
-          thread::spawn(move || {
-            build_from_file(&dict_file);
-          });
-          BuildResult::Success
-        },
-      }
-    },
-    Err(_) => {
-      BuildResult::PathMalformed
+fn open_dict_file(path: &PathBuf) -> Option<File> {
+  match File::open(path) {
+    Ok(file) => Some(file),
+    Err(e) => {
+      error::push_error(ErrorCode::ReadFailed, format!("Failed to open {}: {}", path.display(), e));
+      None
     },
   }
 }
 
-#[no_mangle]
-pub extern "C" fn look_up(
-  chars: *mut *mut c_char,
-  ac_kana: c_char,
-  num_chars: size_t,
-) {
-  let strings_slice = unsafe {
-    std::slice::from_raw_parts(chars, num_chars)
+fn push_user_dict_error(message: &str, e: &std::io::Error) {
+  let code = match e.kind() {
+    ErrorKind::InvalidInput | ErrorKind::NotFound => ErrorCode::InvalidInput,
+    _ => ErrorCode::UserDictSaveFailed,
   };
+  error::push_error(code, format!("{}: {}", message, e));
+}
+
+// writes the changes of the user dictionary if any.
+// save_lock is held from taking the snapshot until writing it
+// so that an older snapshot never overwrites a newer one
+fn write_user_dict(user_dict: &Mutex<Option<UserDict>>, save_lock: &Mutex<()>) -> io::Result<()> {
+  let _save_lock = lock(save_lock);
+  let snapshot = lock(user_dict).as_mut().map(|x| x.take_snapshot()).transpose()?.flatten();
+  let Some(snapshot) = snapshot else {
+    return Ok(());
+  };
+  snapshot.write().inspect_err(|_| {
+    if let Some(user_dict) = lock(user_dict).as_mut() {
+      user_dict.mark_dirty();
+    }
+  })
+}
+
+// how the thread saves the user dictionary
+#[derive(Clone)]
+struct SaverSettings {
+  // SAVE_DELAY except in the tests
+  delay: Duration,
+  // notified after each save by the thread so that the tests can wait for it
+  on_saved: Option<Sender<()>>,
+}
+
+// returns the sender to request saving the user dictionary to the thread that saves it
+// after the delay. the thread exits once the sender is dropped
+fn spawn_user_dict_saver(
+  user_dict: Arc<Mutex<Option<UserDict>>>,
+  save_lock: Arc<Mutex<()>>,
+  settings: SaverSettings,
+) -> Sender<()> {
+  let (sender, receiver) = mpsc::channel();
+  thread::spawn(move || {
+    while receiver.recv().is_ok() {
+      // wait until the changes made in a row are done
+      while receiver.recv_timeout(settings.delay).is_ok() {}
+      if let Err(e) = write_user_dict(&user_dict, &save_lock) {
+        push_user_dict_error("Failed to save user dictionary", &e);
+      }
+      if let Some(on_saved) = &settings.on_saved {
+        let _ = on_saved.send(());
+      }
+    }
+  });
+  sender
+}
+
+// system dictionaries and the user dictionary that lookups go through
+pub struct DictAgent {
+  // dictionaries in the order of priority.
+  // replaced as a whole by build so that lookups in progress keep using the old ones
+  dicts: RwLock<Option<Arc<Vec<SystemDict>>>>,
+  // shared w/ the thread saving it
+  user_dict: Arc<Mutex<Option<UserDict>>>,
+  save_lock: Arc<Mutex<()>>,
+  // requests to save the user dictionary. the thread is spawned by the first change
+  save_requests: Mutex<Option<Sender<()>>>,
+  saver_settings: SaverSettings,
+  // incremented by every build so that only the latest one swaps the dictionaries
+  build_generation: AtomicUsize,
+  build_progress: Mutex<BuildProgress>,
+  // lines skipped while building the dictionaries
+  warnings: Mutex<Vec<DictWarning>>,
+  // whether to build the reverse indexes of the dictionaries
+  reverse_index: AtomicBool,
+  // whether to look up the sorted source files in place instead of building the dictionaries
+  lazy_load: AtomicBool,
+}
+
+impl DictAgent {
+  pub fn new() -> Self {
+    DictAgent {
+      dicts: RwLock::new(None),
+      user_dict: Arc::new(Mutex::new(None)),
+      save_lock: Arc::new(Mutex::new(())),
+      save_requests: Mutex::new(None),
+      saver_settings: SaverSettings { delay: SAVE_DELAY, on_saved: None },
+      build_generation: AtomicUsize::new(0),
+      build_progress: Mutex::new(BuildProgress::default()),
+      warnings: Mutex::new(vec![]),
+      reverse_index: AtomicBool::new(false),
+      lazy_load: AtomicBool::new(false),
+    }
+  }
+
+  // replaces the warnings of the path as the dictionary can be rebuilt
+  fn add_warnings(&self, path: &Path, warnings: &[ParseWarning]) {
+    let mut all_warnings = lock(&self.warnings);
+    all_warnings.retain(|x| x.path != path);
+    for warning in warnings {
+      all_warnings.push(DictWarning {
+        path: path.to_path_buf(),
+        warning: warning.clone(),
+      });
+    }
+  }
+
+  fn warnings(&self) -> Vec<String> {
+    lock(&self.warnings).iter().map(|x| x.to_string()).collect()
+  }
+
+  // builds superseded by a newer one don't report their progress
+  fn update_build_progress(&self, generation: usize, f: impl FnOnce(&mut BuildProgress)) {
+    let mut progress = lock(&self.build_progress);
+    if generation == self.build_generation.load(Ordering::SeqCst) {
+      f(&mut progress);
+    }
+  }
+
+  fn build_progress(&self) -> BuildProgress {
+    *lock(&self.build_progress)
+  }
+
+  fn get_dicts(&self) -> Option<Arc<Vec<SystemDict>>> {
+    read(&self.dicts).clone()
+  }
+
+  // parses the dictionary while reading the file.
+  // the progress is of the bytes read from the file so that it is known for gzip as well
+  fn build_dict_from_file(&self, generation: usize, path: &PathBuf, is_gz: bool) -> Option<Dict> {
+    let file = open_dict_file(path)?;
+    let num_bytes = file.metadata().map_or(0, |x| x.len() as usize);
+    let num_bytes_read = Cell::new(0);
+    let file = CountingReader { inner: file, num_bytes_read: &num_bytes_read };
 
-  let mut reading = vec![];
-  for &c_str_ptr in strings_slice.iter() {
-    let c_str = unsafe {
-      std::ffi::CStr::from_ptr(c_str_ptr)
+    self.update_build_progress(generation, |x| {
+      x.state = BuildState::Parsing;
+      x.num_bytes_done = 0;
+      x.num_bytes = num_bytes;
+    });
+    let on_progress = &mut |_| {
+      self.update_build_progress(generation, |x| x.num_bytes_done = num_bytes_read.get());
     };
-    match c_str.to_str() {
-      Ok(s) => {
-        for c in s.chars() {
-          reading.push(c);
+    let res = if is_gz {
+      Dict::build_from_reader(&mut BufReader::new(GzDecoder::new(file)), on_progress)
+    } else {
+      Dict::build_from_reader(&mut BufReader::new(file), on_progress)
+    };
+
+    match res {
+      Ok((dict, warnings)) => {
+        self.add_warnings(path, &warnings);
+        Some(dict)
+      },
+      Err(e) => {
+        error::push_error(ErrorCode::ReadFailed, format!("Failed to read {}: {}", path.display(), e));
+        None
+      },
+    }
+  }
+
+  // writes the mmap cache and returns the dictionary mapped from it.
+  // the built dictionary is used as it is if the cache cannot be written
+  fn gen_mmap(&self, generation: usize, dict: Dict, path_mmap: &PathBuf) -> SystemDict {
+    self.update_build_progress(generation, |x| x.state = BuildState::Serializing);
+    match MmapDict::write(&dict, path_mmap).and_then(|_| MmapDict::open(path_mmap)) {
+      Ok(mmap_dict) => SystemDict::Mapped(mmap_dict),
+      Err(e) => {
+        error::push_error(
+          ErrorCode::CacheSaveFailed,
+          format!("Failed to save {}: {}", path_mmap.display(), e),
+        );
+        SystemDict::Tree(dict)
+      },
+    }
+  }
+
+  // applies to the dictionaries built next time
+  pub fn set_reverse_index(&self, enabled: bool) {
+    self.reverse_index.store(enabled, Ordering::SeqCst);
+  }
+
+  // applies to the dictionaries built next time
+  pub fn set_lazy_load(&self, enabled: bool) {
+    self.lazy_load.store(enabled, Ordering::SeqCst);
+  }
+
+  fn needs_reverse_index(&self) -> bool {
+    self.reverse_index.load(Ordering::SeqCst)
+  }
+
+  fn build_reverse_index(&self, dict: &mut Dict) {
+    if self.needs_reverse_index() && !dict.has_reverse_index() {
+      dict.build_reverse_index();
+    }
+  }
+
+  // builds the dictionary from the source w/o the caches
+  fn rebuild_from_source(&self, generation: usize, base_path: &PathBuf) -> Option<SystemDict> {
+    match get_dict_file(base_path, false) {
+      DictFile::NotFound => None,
+      dict_file => self.build_from_file(generation, &dict_file),
+    }
+  }
+
+  fn build_from_file(&self, generation: usize, dict_file: &DictFile) -> Option<SystemDict> {
+    match dict_file {
+      DictFile::Mapped(path_mmap, base_path) => {
+        self.update_build_progress(generation, |x| x.state = BuildState::LoadingCache);
+        match MmapDict::open(path_mmap) {
+          Ok(mmap_dict) => {
+            // the cache w/o the index is rebuilt if the source is available
+            if self.needs_reverse_index() && !mmap_dict.has_reverse_index() {
+              if let Some(dict) = self.rebuild_from_source(generation, base_path) {
+                return Some(dict);
+              }
+            }
+            Some(SystemDict::Mapped(mmap_dict))
+          },
+          Err(e) => {
+            error::push_error(
+              ErrorCode::CacheLoadFailed,
+              format!("Rebuilding {} from the source: {}", path_mmap.display(), e),
+            );
+            self.rebuild_from_source(generation, base_path)
+          },
+        }
+      },
+      DictFile::SerGz(path_ser_gz, base_path, path_mmap) => {
+        self.update_build_progress(generation, |x| x.state = BuildState::LoadingCache);
+        match Dict::deserialize_from_file(path_ser_gz) {
+          Ok(mut dict) => {
+            // converted to the mmap cache not to deserialize it every time
+            self.build_reverse_index(&mut dict);
+            Some(self.gen_mmap(generation, dict, path_mmap))
+          },
+          Err(e) => {
+            error::push_error(
+              ErrorCode::CacheLoadFailed,
+              format!("Rebuilding {} from the source: {}", path_ser_gz.display(), e),
+            );
+
+            // ser.gz can be of an older version. rebuild it from the source
+            self.rebuild_from_source(generation, base_path)
+          },
         }
       },
-      Err(e) => panic!("Failed to convert to Rust string: {e}"),
+      DictFile::Gz(path_gz, path_mmap) => {
+        let mut dict = self.build_dict_from_file(generation, path_gz, true)?;
+        self.build_reverse_index(&mut dict);
+        Some(self.gen_mmap(generation, dict, path_mmap))
+      },
+      DictFile::Raw(path_raw, path_mmap) => {
+        let mut dict = self.build_dict_from_file(generation, path_raw, false)?;
+        self.build_reverse_index(&mut dict);
+        Some(self.gen_mmap(generation, dict, path_mmap))
+      },
+      DictFile::Sorted(path_raw, path_mmap) => {
+        self.update_build_progress(generation, |x| x.state = BuildState::LoadingCache);
+        match SortedDict::open(path_raw) {
+          Ok(sorted_dict) => Some(SystemDict::Sorted(sorted_dict)),
+          Err(e) => {
+            error::push_error(
+              ErrorCode::ReadFailed,
+              format!("Loading {} as it cannot be looked up in place: {}", path_raw.display(), e),
+            );
+            self.build_from_file(generation, &DictFile::Raw(path_raw.clone(), path_mmap.clone()))
+          },
+        }
+      },
+      DictFile::NotFound => {
+        error::push_error(
+          ErrorCode::Internal,
+          "should not be visited. check code (dict_agent 1)".to_string(),
+        );
+        None
+      }
+    }
+  }
+
+  // builds the dictionaries of the paths in the background and returns the result of each path.
+  // None in the paths means a malformed path
+  pub fn build(self: &Arc<Self>, paths: Vec<Option<PathBuf>>) -> Vec<BuildResult> {
+    let mut dict_files = vec![];
+    let mut build_results = vec![];
+    let lazy_load = self.lazy_load.load(Ordering::SeqCst);
+
+    for path in paths {
+      let build_result = match path {
+        Some(path) => {
+          match get_dict_file_to_load(&path, lazy_load) {
+            DictFile::NotFound => BuildResult::FileNotFound,
+            dict_file => {
+              dict_files.push(dict_file);
+              BuildResult::Success
+            },
+          }
+        },
+        None => BuildResult::PathMalformed,
+      };
+      build_results.push(build_result);
+    }
+
+    let generation = self.build_generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+    if dict_files.is_empty() {
+      self.update_build_progress(generation, |x| {
+        *x = BuildProgress { state: BuildState::Failed, ..BuildProgress::default() };
+      });
+    } else {
+      let num_dicts = dict_files.len();
+      self.update_build_progress(generation, |x| {
+        *x = BuildProgress { num_dicts, ..BuildProgress::default() };
+      });
+
+      // the agent should outlive the build even if its handle is destroyed
+      let agent = Arc::clone(self);
+      thread::spawn(move || {
+        // a panic while building fails the build keeping the old dictionaries
+        let dicts = panic::catch_unwind(AssertUnwindSafe(|| {
+          let mut dicts = vec![];
+          for (i, dict_file) in dict_files.iter().enumerate() {
+            agent.update_build_progress(generation, |x| {
+              x.num_dicts_done = i;
+              x.num_bytes_done = 0;
+              x.num_bytes = 0;
+            });
+            if let Some(dict) = agent.build_from_file(generation, dict_file) {
+              dicts.push(dict);
+            }
+          }
+          dicts
+        })).unwrap_or_else(|payload| {
+          error::push_error(
+            ErrorCode::Internal,
+            format!("build panicked: {}", error::panic_message(payload.as_ref())),
+          );
+          vec![]
+        });
+        let state = if dicts.is_empty() { BuildState::Failed } else { BuildState::Ready };
+
+        // the old dictionaries are kept if none could be built
+        let mut curr_dicts = write(&agent.dicts);
+        if state == BuildState::Ready
+          && generation == agent.build_generation.load(Ordering::SeqCst) {
+          *curr_dicts = Some(Arc::new(dicts));
+        }
+        // look_up should see the dictionaries once the state becomes ready
+        agent.update_build_progress(generation, |x| {
+          x.state = state;
+          x.num_dicts_done = num_dicts;
+        });
+      });
     }
+    build_results
   }
-  RESULT_CACHE.lock().unwrap().clear();
 
-  match &DICT.get() {
-    Some(dict) => {
-      let dict = dict.lock().unwrap();
-      let ac_kana = {
-        let ac_kana = ac_kana as u8 as char;
-        if ac_kana  == ' ' {
-          None
-        } else {
-          Some(ac_kana)
+  pub fn load_user_dict(&self, path: Option<PathBuf>) -> BuildResult {
+    // not to lose the changes of the dictionary to replace
+    self.save_user_dict();
+
+    match path {
+      Some(path) => {
+        match UserDict::load(&path) {
+          Ok(user_dict) => {
+            self.add_warnings(&path, user_dict.warnings());
+            *lock(&self.user_dict) = Some(user_dict);
+            BuildResult::Success
+          },
+          Err(e) => {
+            error::push_error(
+              ErrorCode::UserDictLoadFailed,
+              format!("Failed to load {}: {}", path.display(), e),
+            );
+            // a missing file is loaded as an empty dictionary,
+            // so this is only the case if it is removed while being read
+            match e.kind() {
+              ErrorKind::NotFound => BuildResult::FileNotFound,
+              _ => BuildResult::Failed,
+            }
+          },
         }
+      },
+      None => BuildResult::PathMalformed,
+    }
+  }
+
+  fn look_up_dicts(
+    &self,
+    reading: &[char],
+    ac_kana: &Option<char>,
+    okuri: &str,
+  ) -> Option<Vec<Candidate>> {
+    let dicts = self.get_dicts()?;
+    SystemDict::look_up_in_order(&dicts, reading, ac_kana, okuri)
+  }
+
+  // candidates in the user dictionary come first
+  fn look_up_merged(&self, reading: &[char], ac_kana: &Option<char>, okuri: &str) -> Vec<Candidate> {
+    let system_kanjis = self.look_up_dicts(reading, ac_kana, okuri);
+
+    match lock(&self.user_dict).as_ref() {
+      Some(user_dict) => user_dict.merge(reading, ac_kana, okuri, system_kanjis.as_ref()),
+      None => system_kanjis.unwrap_or_default(),
+    }
+  }
+
+  // okuri is the okuri kana such as し of 悔し. candidates of the okuri block come first.
+  // it can be empty to look up w/o it
+  pub fn look_up(&self, reading: &[char], ac_kana: &Option<char>, okuri: &str) -> LookUpResults {
+    let mut results = LookUpResults::default();
+    results.render_all(self.look_up_merged(reading, ac_kana, okuri));
+
+    // a reading w/ numbers such as 12がつ is also looked up as #がつ
+    if let Some((numeric_reading, numbers)) = numeric::to_numeric_reading(reading) {
+      let kanjis = self.look_up_merged(&numeric_reading, ac_kana, okuri);
+      let look_up_number = |number: &str| {
+        let reading: Vec<char> = number.chars().collect();
+        self.look_up_merged(&reading, &None, "").into_iter()
+          .find_map(|x| lisp::render(&x.kanji))
       };
-      if let Some(res) = dict.look_up(&reading, &ac_kana) {
-        for s in res {
-          RESULT_CACHE.lock().unwrap().push(s.to_string());
+      results.render_numeric(&numeric_reading, &numbers, kanjis, &look_up_number);
+    }
+    results
+  }
+
+  // returns up to limit okuri-nasi readings starting with the prefix.
+  // readings in the user dictionary come first.
+  // the ones whose candidates are all purged by the user are skipped
+  pub fn complete(&self, prefix: &[char], limit: usize) -> LookUpResults {
+    let mut readings: Vec<String> = vec![];
+
+    let has_user_dict = match lock(&self.user_dict).as_ref() {
+      Some(user_dict) => {
+        readings = user_dict.complete(prefix, limit);
+        true
+      },
+      None => false,
+    };
+    if let Some(dicts) = self.get_dicts() {
+      for dict in dicts.iter() {
+        for reading in dict.complete(prefix, limit) {
+          if readings.len() == limit {
+            break;
+          }
+          if readings.contains(&reading) {
+            continue;
+          }
+          let chars: Vec<char> = reading.chars().collect();
+          if has_user_dict && self.look_up_merged(&chars, &None, "").is_empty() {
+            continue;
+          }
+          readings.push(reading);
         }
       }
-    },
-    None => (),
-  };
-}
+    }
+    let mut results = LookUpResults::default();
+    for reading in readings {
+      results.push_unique(Candidate::new(&reading), reading, None);
+    }
+    results
+  }
 
-#[no_mangle]
-// results: pointer to byte buffers. each buffer is expected to be large enough to hold a result
-// buf_size: size of the byte buffer
-// offset: start index of the results to return
-// num_results: [in] maximum # of results [out] # of returned results
-pub extern "C" fn get_results(
-  results: *mut *mut c_char,
-  buf_size: size_t,
-  offset: size_t,
-  num_results: *mut size_t,
-) {
-  let result_cache = &RESULT_CACHE.lock().unwrap();
-  let safe_num_results = unsafe { *num_results };
-  let from = offset;
-  let to = usize::min(offset + safe_num_results, result_cache.len());
-  
-  let results = unsafe {
-    slice::from_raw_parts_mut(results, safe_num_results)
-  };
+  // returns the readings of the candidate such as くや w/ s and し for 悔.
+  // readings in the user dictionary come first
+  pub fn reverse_look_up(&self, candidate: &str) -> LookUpResults {
+    let mut results = LookUpResults::default();
+
+    if let Some(user_dict) = lock(&self.user_dict).as_ref() {
+      for entry in user_dict.look_up_readings(candidate) {
+        results.push_reading(entry);
+      }
+    }
+    if let Some(dicts) = self.get_dicts() {
+      for dict in dicts.iter() {
+        for entry in dict.look_up_readings(candidate) {
+          results.push_reading(entry);
+        }
+      }
+    }
+    results
+  }
 
-  let mut i: size_t = 0;
+  // results are the ones the candidate is selected from
+  pub fn commit(
+    &self,
+    results: &LookUpResults,
+    reading: Vec<char>,
+    ac_kana: &Option<char>,
+    okuri: &str,
+    candidate: &str,
+  ) {
+    // learn lisp and numeric candidates as they are not to fix the evaluated result
+    let (reading, candidate) = results.to_dict_entry(reading, candidate);
 
-  for result in result_cache[from..to].iter() {
-    let dest = results[i];
-    unsafe {
-      let src = result.as_ptr() as *const c_char;
-      // -1 for null-termination space
-      let len = usize::min(result.len(), buf_size - 1);
+    match lock(&self.user_dict).as_mut() {
+      Some(user_dict) => {
+        match user_dict.commit(&reading, ac_kana, okuri, &candidate) {
+          Ok(()) => self.request_save(),
+          Err(e) => push_user_dict_error("Failed to commit candidate", &e),
+        }
+      },
+      None => {
+        error::push_error(ErrorCode::UserDictNotLoaded, "User dictionary is not loaded".to_string());
+      },
+    }
+  }
 
-      ptr::copy(src, dest, len);
-      *dest.add(len) = 0; // null-terminate
+  pub fn register(
+    &self,
+    reading: &[char],
+    ac_kana: &Option<char>,
+    okuri: &str,
+    word: &str,
+  ) -> UserDictResult {
+    match lock(&self.user_dict).as_mut() {
+      Some(user_dict) => {
+        match user_dict.register(reading, ac_kana, okuri, word) {
+          Ok(()) => {
+            self.request_save();
+            UserDictResult::Success
+          },
+          Err(e) => {
+            push_user_dict_error("Failed to register word", &e);
+            UserDictResult::Failed
+          },
+        }
+      },
+      None => UserDictResult::UserDictNotLoaded,
     }
+  }
+
+  pub fn purge(
+    &self,
+    results: &LookUpResults,
+    reading: Vec<char>,
+    ac_kana: &Option<char>,
+    candidate: &str,
+  ) -> UserDictResult {
+    let (reading, candidate) = results.to_dict_entry(reading, candidate);
 
-    i += 1;
-    if i == safe_num_results {
-      break;
+    let in_system_dict = self.look_up_dicts(&reading, ac_kana, "")
+      .is_some_and(|kanjis| kanjis.iter().any(|x| x.kanji == candidate));
+
+    match lock(&self.user_dict).as_mut() {
+      Some(user_dict) => {
+        match user_dict.purge(&reading, ac_kana, &candidate, in_system_dict) {
+          Ok(()) => {
+            self.request_save();
+            UserDictResult::Success
+          },
+          Err(e) => {
+            push_user_dict_error("Failed to purge candidate", &e);
+            UserDictResult::Failed
+          },
+        }
+      },
+      None => UserDictResult::UserDictNotLoaded,
     }
   }
 
-  // return the number of copied results to the caller
-  unsafe { *num_results = i };
+  // the user dictionary is saved later by the thread
+  fn request_save(&self) {
+    let mut sender = lock(&self.save_requests);
+    let sender = sender.get_or_insert_with(|| {
+      spawn_user_dict_saver(
+        Arc::clone(&self.user_dict),
+        Arc::clone(&self.save_lock),
+        self.saver_settings.clone(),
+      )
+    });
+    // the thread only exits after the sender is dropped
+    let _ = sender.send(());
+  }
+
+  // writes the changes of the user dictionary right away
+  pub fn save_user_dict(&self) -> UserDictResult {
+    if lock(&self.user_dict).is_none() {
+      return UserDictResult::UserDictNotLoaded;
+    }
+    match write_user_dict(&self.user_dict, &self.save_lock) {
+      Ok(()) => UserDictResult::Success,
+      Err(e) => {
+        push_user_dict_error("Failed to save user dictionary", &e);
+        UserDictResult::Failed
+      },
+    }
+  }
+}
+
+// the pending changes are saved before the thread is stopped
+impl Drop for DictAgent {
+  fn drop(&mut self) {
+    self.save_user_dict();
+  }
+}
+
+impl Default for DictAgent {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn invalid_input(message: String) -> AgentError {
+  AgentError::new(ErrorCode::InvalidInput, message)
+}
+
+// null or non-UTF-8 paths are malformed ones
+fn to_path_buf(path: *const c_char) -> Option<PathBuf> {
+  let path = to_str(path).ok()?;
+  let path = shellexpand::tilde(path);
+  PathBuf::from_str(&path).ok()
 }
 
+fn to_slice<'a, T>(ptr: *const T, len: size_t) -> Result<&'a [T], AgentError> {
+  if len == 0 {
+    Ok(&[])
+  } else if ptr.is_null() {
+    Err(invalid_input("Array is null".to_string()))
+  } else {
+    Ok(unsafe { slice::from_raw_parts(ptr, len) })
+  }
+}
+
+fn to_readings(chars: *mut *mut c_char, num_chars: size_t) -> Result<Vec<char>, AgentError> {
+  let mut readings = vec![];
+  for &c_str_ptr in to_slice(chars, num_chars)? {
+    readings.extend(to_str(c_str_ptr)?.chars());
+  }
+  Ok(readings)
+}
+
+// ' ' means no accompanying kana
+fn to_acc_kana(ac_kana: c_char) -> Option<char> {
+  let ac_kana = ac_kana as u8 as char;
+  if ac_kana  == ' ' {
+    None
+  } else {
+    Some(ac_kana)
+  }
+}
+
+// okuri is given as the romaji prefix followed by the okuri kana such as "sし".
+// the prefix is the accompanying kana of the dictionaries. an empty string means no okuri
+fn parse_okuri(okuri: &str) -> (Option<char>, String) {
+  let mut chars = okuri.trim().chars();
+  let ac_kana = chars.next();
+  (ac_kana, chars.collect())
+}
+
+fn to_okuri(okuri: *const c_char) -> Result<(Option<char>, String), AgentError> {
+  Ok(parse_okuri(to_str(okuri)?))
+}
+
+fn to_path_bufs(paths: *mut *mut c_char, num_paths: size_t) -> Result<Vec<Option<PathBuf>>, AgentError> {
+  Ok(to_slice(paths, num_paths)?.iter().map(|&path| to_path_buf(path)).collect())
+}
+
+fn to_chars(s: *const c_char) -> Result<Vec<char>, AgentError> {
+  Ok(to_str(s)?.chars().collect())
+}
+
+fn to_str<'a>(s: *const c_char) -> Result<&'a str, AgentError> {
+  if s.is_null() {
+    return Err(invalid_input("String is null".to_string()));
+  }
+  unsafe { CStr::from_ptr(s) }.to_str()
+    .map_err(|e| invalid_input(format!("String is not UTF-8: {}", e)))
+}
+
+// writes the result of each path to results if not null and returns the first failure
+fn return_build_results(build_results: Vec<BuildResult>, results: *mut BuildResult) -> BuildResult {
+  if !results.is_null() {
+    let results = unsafe {
+      slice::from_raw_parts_mut(results, build_results.len())
+    };
+    results.copy_from_slice(&build_results);
+  }
+
+  build_results.into_iter()
+    .find(|x| *x != BuildResult::Success)
+    .unwrap_or(BuildResult::Success)
+}
+
+#[no_mangle]
+// dict_file_paths: dictionary file paths in the order of priority
+// num_paths: # of the paths
+// results: [out] build result of each path. can be null
+//
+// dictionaries that are not found are skipped and the first failure is returned.
+// can be called again to reload the dictionaries. the current ones serve lookups
+// until the new ones are built
+pub extern "C" fn build(
+  dict_file_paths: *mut *mut c_char,
+  num_paths: size_t,
+  results: *mut BuildResult,
+) -> BuildResult {
+  error::catch_errors("build", BuildResult::Failed, || {
+    let paths = to_path_bufs(dict_file_paths, num_paths)?;
+    Ok(return_build_results(DEFAULT_AGENT.build(paths), results))
+  })
+}
+
+#[no_mangle]
+// progress: [out] progress of the dictionaries being built by build
+pub extern "C" fn get_build_progress(progress: *mut BuildProgress) {
+  error::catch_errors("get_build_progress", (), || {
+    set_progress(progress, DEFAULT_AGENT.build_progress())
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn load_user_dict(
+  user_dict_file_path: *const c_char,
+) -> BuildResult {
+  error::catch_errors("load_user_dict", BuildResult::Failed, || {
+    Ok(DEFAULT_AGENT.load_user_dict(to_path_buf(user_dict_file_path)))
+  })
+}
+
+#[no_mangle]
+// deprecated in favor of dict_look_up. the reading is passed as the chars split by the caller
+// and ac_kana is a single byte, so the okuri kana cannot be passed to look up the okuri blocks
+#[deprecated(note = "use dict_look_up")]
+pub extern "C" fn look_up(
+  chars: *mut *mut c_char,
+  ac_kana: c_char,
+  num_chars: size_t,
+) {
+  error::catch_errors("look_up", (), || {
+    let reading = to_readings(chars, num_chars)?;
+    let ac_kana = to_acc_kana(ac_kana);
+    *lock(&RESULT_CACHE) = DEFAULT_AGENT.look_up(&reading, &ac_kana, "");
+    Ok(())
+  })
+}
+
+#[no_mangle]
+// stores up to limit okuri-nasi readings starting with the prefix to the result cache.
+// readings in the user dictionary come first.
+// deprecated in favor of dict_complete that takes the prefix as a UTF-8 string
+#[deprecated(note = "use dict_complete")]
+pub extern "C" fn complete(
+  chars: *mut *mut c_char,
+  num_chars: size_t,
+  limit: size_t,
+) {
+  error::catch_errors("complete", (), || {
+    let prefix = to_readings(chars, num_chars)?;
+    *lock(&RESULT_CACHE) = DEFAULT_AGENT.complete(&prefix, limit);
+    Ok(())
+  })
+}
+
+#[no_mangle]
+// notifies the candidate selected by the user so that it comes first next time.
+// deprecated in favor of dict_commit_candidate as look_up
+#[deprecated(note = "use dict_commit_candidate")]
+pub extern "C" fn commit_candidate(
+  chars: *mut *mut c_char,
+  ac_kana: c_char,
+  num_chars: size_t,
+  candidate: *const c_char,
+) {
+  error::catch_errors("commit_candidate", (), || {
+    let reading = to_readings(chars, num_chars)?;
+    let ac_kana = to_acc_kana(ac_kana);
+    let results = lock(&RESULT_CACHE);
+    DEFAULT_AGENT.commit(&results, reading, &ac_kana, "", to_str(candidate)?);
+    Ok(())
+  })
+}
+
+#[no_mangle]
+// registers a word for the reading that the user dictionary doesn't know yet.
+// the word is returned from look_up right after the registration.
+// deprecated in favor of dict_register_word as look_up
+#[deprecated(note = "use dict_register_word")]
+pub extern "C" fn register_word(
+  chars: *mut *mut c_char,
+  ac_kana: c_char,
+  num_chars: size_t,
+  word: *const c_char,
+) -> UserDictResult {
+  error::catch_errors("register_word", UserDictResult::Failed, || {
+    let reading = to_readings(chars, num_chars)?;
+    let ac_kana = to_acc_kana(ac_kana);
+    Ok(DEFAULT_AGENT.register(&reading, &ac_kana, "", to_str(word)?))
+  })
+}
+
+#[no_mangle]
+// removes the candidate from the user dictionary.
+// the candidate is hidden instead if it comes from the system dictionary.
+// deprecated in favor of dict_purge_candidate as look_up
+#[deprecated(note = "use dict_purge_candidate")]
+pub extern "C" fn purge_candidate(
+  chars: *mut *mut c_char,
+  ac_kana: c_char,
+  num_chars: size_t,
+  candidate: *const c_char,
+) -> UserDictResult {
+  error::catch_errors("purge_candidate", UserDictResult::Failed, || {
+    let reading = to_readings(chars, num_chars)?;
+    let ac_kana = to_acc_kana(ac_kana);
+    let results = lock(&RESULT_CACHE);
+    Ok(DEFAULT_AGENT.purge(&results, reading, &ac_kana, to_str(candidate)?))
+  })
+}
+
+#[no_mangle]
+// writes the changes of the user dictionary right away.
+// they are saved in the background a while after they are made,
+// so this is to be called before exiting not to lose the latest ones
+pub extern "C" fn save_user_dict() -> UserDictResult {
+  error::catch_errors("save_user_dict", UserDictResult::Failed, || {
+    Ok(DEFAULT_AGENT.save_user_dict())
+  })
+}
+
+fn set_progress(progress: *mut BuildProgress, value: BuildProgress) -> Result<(), AgentError> {
+  if progress.is_null() {
+    return Err(invalid_input("progress is null".to_string()));
+  }
+  unsafe { *progress = value };
+  Ok(())
+}
+
+// copies the null-terminated string to the byte buffer.
+// the string is truncated at a char boundary if the buffer is not large enough
+fn copy_to_buf(s: &str, dest: *mut c_char, buf_size: size_t) {
+  if dest.is_null() || buf_size == 0 {
+    return;
+  }
+  // -1 for null-termination space
+  let mut len = usize::min(s.len(), buf_size - 1);
+  while !s.is_char_boundary(len) {
+    len -= 1;
+  }
+  unsafe {
+    ptr::copy(s.as_ptr() as *const c_char, dest, len);
+    *dest.add(len) = 0; // null-terminate
+  }
+}
+
+// copies strs[offset..] to the byte buffers up to *num_results
+fn copy_to_bufs(
+  strs: &[&str],
+  results: *mut *mut c_char,
+  buf_size: size_t,
+  offset: size_t,
+  num_results: *mut size_t,
+) -> Result<(), AgentError> {
+  if num_results.is_null() {
+    return Err(invalid_input("num_results is null".to_string()));
+  }
+  let safe_num_results = unsafe { *num_results };
+  let strs = strs.get(offset..).unwrap_or_default();
+  let results = to_slice(results, usize::min(safe_num_results, strs.len()))?;
+
+  for (result, buf) in strs.iter().zip(results) {
+    copy_to_buf(result, *buf, buf_size);
+  }
+
+  // return the number of copied results to the caller
+  unsafe { *num_results = results.len() };
+  Ok(())
+}
+
+#[no_mangle]
+// results: pointer to byte buffers. each buffer is expected to be large enough to hold a result
+// buf_size: size of the byte buffer
+// offset: start index of the results to return
+// num_results: [in] maximum # of results [out] # of returned results
+pub extern "C" fn get_results(
+  results: *mut *mut c_char,
+  buf_size: size_t,
+  offset: size_t,
+  num_results: *mut size_t,
+) {
+  error::catch_errors("get_results", (), || {
+    let result_cache = lock(&RESULT_CACHE);
+    copy_to_bufs(&result_cache.rendered(), results, buf_size, offset, num_results)
+  })
+}
+
+#[no_mangle]
+// same as get_results but returns the annotations of the results.
+// an empty string is returned for a result w/o annotation
+pub extern "C" fn get_annotations(
+  annotations: *mut *mut c_char,
+  buf_size: size_t,
+  offset: size_t,
+  num_annotations: *mut size_t,
+) {
+  error::catch_errors("get_annotations", (), || {
+    let result_cache = lock(&RESULT_CACHE);
+    copy_to_bufs(&result_cache.annotations(), annotations, buf_size, offset, num_annotations)
+  })
+}
+
+#[no_mangle]
+// returns the # of lines skipped while building the dictionaries
+pub extern "C" fn get_num_warnings() -> size_t {
+  error::catch_errors("get_num_warnings", 0, || Ok(lock(&DEFAULT_AGENT.warnings).len()))
+}
+
+#[no_mangle]
+// same as get_results but returns the skipped lines in the form of path:line number: reason
+pub extern "C" fn get_warnings(
+  warnings: *mut *mut c_char,
+  buf_size: size_t,
+  offset: size_t,
+  num_warnings: *mut size_t,
+) {
+  error::catch_errors("get_warnings", (), || {
+    let strs = DEFAULT_AGENT.warnings();
+    let strs: Vec<&str> = strs.iter().map(|x| x.as_str()).collect();
+    copy_to_bufs(&strs, warnings, buf_size, offset, num_warnings)
+  })
+}
+
+#[no_mangle]
+// message: [out] byte buffer to receive the message of the oldest error
+// buf_size: size of the byte buffer
+//
+// removes the oldest error from the queue and returns its code.
+// NoError is returned if the queue is empty.
+// the queue is shared by the agents so the errors of all of them are returned
+pub extern "C" fn get_last_error(
+  message: *mut c_char,
+  buf_size: size_t,
+) -> ErrorCode {
+  // not to queue another error while draining the queue
+  panic::catch_unwind(|| {
+    match error::pop_error() {
+      Some(e) => {
+        copy_to_buf(&e.message, message, buf_size);
+        e.code
+      },
+      None => ErrorCode::NoError,
+    }
+  }).unwrap_or(ErrorCode::Internal)
+}
+
+// handle-based functions.
+// unlike the ones above, each agent has its own dictionaries and each lookup returns
+// its own result set so that several of them can be used at the same time.
+// agents and result sets must be destroyed by the caller.
+// errors are not kept per agent. get_last_error returns the ones of all the agents.
+// readings are given as a UTF-8 string and the okuri as the romaji prefix followed by the kana.
+// the ones above taking the reading as char** are kept only for the existing callers.
+//
+// handle contract: a live agent is one returned by dict_create and not destroyed yet, and
+// a live result set is one returned by a dict_* function and not destroyed yet.
+// null handles are reported as InvalidInput errors but any other pointer that is not
+// a live handle is undefined behavior, which is why the functions taking them are unsafe
+
+// the agent is shared w/ the build in progress
+type AgentHandle = Arc<DictAgent>;
+
+// the agent must be null or a live one returned by dict_create
+unsafe fn to_agent<'a>(agent: *const AgentHandle) -> Result<&'a AgentHandle, AgentError> {
+  if agent.is_null() {
+    return Err(invalid_input("Agent is null".to_string()));
+  }
+  Ok(&*agent)
+}
+
+// the result set must be null or a live one returned by a dict_* function
+unsafe fn to_results<'a>(results: *const LookUpResults) -> Result<&'a LookUpResults, AgentError> {
+  if results.is_null() {
+    return Err(invalid_input("Result set is null".to_string()));
+  }
+  Ok(&*results)
+}
+
+#[no_mangle]
+// returns a new agent w/o dictionaries
+pub extern "C" fn dict_create() -> *mut AgentHandle {
+  error::catch_errors("dict_create", ptr::null_mut(), || {
+    Ok(Box::into_raw(Box::new(Arc::new(DictAgent::new()))))
+  })
+}
+
+#[no_mangle]
+/// a build in progress keeps running until it finishes and then discards the dictionaries
+///
+/// # Safety
+/// `agent` must be null or a live agent. it must not be used afterwards
+pub unsafe extern "C" fn dict_destroy(agent: *mut AgentHandle) {
+  error::catch_errors("dict_destroy", (), || {
+    if !agent.is_null() {
+      drop(unsafe { Box::from_raw(agent) });
+    }
+    Ok(())
+  })
+}
+
+#[no_mangle]
+/// same as build but for the agent
+///
+/// # Safety
+/// `agent` must be null or a live agent.
+/// `dict_file_paths` must point to `num_paths` null-terminated strings and
+/// `results` must be null or have room for `num_paths` results
+pub unsafe extern "C" fn dict_build(
+  agent: *mut AgentHandle,
+  dict_file_paths: *mut *mut c_char,
+  num_paths: size_t,
+  results: *mut BuildResult,
+) -> BuildResult {
+  error::catch_errors("dict_build", BuildResult::Failed, || {
+    let paths = to_path_bufs(dict_file_paths, num_paths)?;
+    Ok(return_build_results(to_agent(agent)?.build(paths), results))
+  })
+}
+
+#[no_mangle]
+/// # Safety
+/// `agent` must be null or a live agent.
+pub unsafe extern "C" fn dict_get_build_progress(agent: *mut AgentHandle, progress: *mut BuildProgress) {
+  error::catch_errors("dict_get_build_progress", (), || {
+    set_progress(progress, to_agent(agent)?.build_progress())
+  })
+}
+
+#[no_mangle]
+/// # Safety
+/// `agent` must be null or a live agent.
+/// `user_dict_file_path` must be null or a null-terminated string
+pub unsafe extern "C" fn dict_load_user_dict(
+  agent: *mut AgentHandle,
+  user_dict_file_path: *const c_char,
+) -> BuildResult {
+  error::catch_errors("dict_load_user_dict", BuildResult::Failed, || {
+    Ok(to_agent(agent)?.load_user_dict(to_path_buf(user_dict_file_path)))
+  })
+}
+
+#[no_mangle]
+/// reading: reading w/o okuri such as くや of 悔し
+/// okuri: romaji prefix followed by the okuri kana such as "sし". empty for okuri-nasi
+///
+/// returns a new result set of the candidates. null is returned on error
+///
+/// # Safety
+/// `agent` must be null or a live agent.
+/// `reading` and `okuri` must be null or null-terminated strings
+pub unsafe extern "C" fn dict_look_up(
+  agent: *mut AgentHandle,
+  reading: *const c_char,
+  okuri: *const c_char,
+) -> *mut LookUpResults {
+  error::catch_errors("dict_look_up", ptr::null_mut(), || {
+    let reading = to_chars(reading)?;
+    let (ac_kana, okuri) = to_okuri(okuri)?;
+    Ok(Box::into_raw(Box::new(to_agent(agent)?.look_up(&reading, &ac_kana, &okuri))))
+  })
+}
+
+#[no_mangle]
+/// returns a new result set of up to limit readings starting with the prefix.
+/// null is returned on error
+///
+/// # Safety
+/// `agent` must be null or a live agent.
+/// `prefix` must be null or a null-terminated string
+pub unsafe extern "C" fn dict_complete(
+  agent: *mut AgentHandle,
+  prefix: *const c_char,
+  limit: size_t,
+) -> *mut LookUpResults {
+  error::catch_errors("dict_complete", ptr::null_mut(), || {
+    let prefix = to_chars(prefix)?;
+    Ok(Box::into_raw(Box::new(to_agent(agent)?.complete(&prefix, limit))))
+  })
+}
+
+#[no_mangle]
+/// enabled: whether to build the reverse indexes of the dictionaries by dict_build.
+/// dict_reverse_look_up works w/o them but takes time for large dictionaries
+///
+/// # Safety
+/// `agent` must be null or a live agent
+pub unsafe extern "C" fn dict_set_reverse_index(agent: *mut AgentHandle, enabled: bool) {
+  error::catch_errors("dict_set_reverse_index", (), || {
+    to_agent(agent)?.set_reverse_index(enabled);
+    Ok(())
+  })
+}
+
+#[no_mangle]
+/// enabled: whether dict_build looks up uncompressed dictionaries in the source files
+/// instead of building them in memory. the files must be sorted as SKK-JISYO files are.
+/// startup gets instant at the cost of slower lookups
+///
+/// # Safety
+/// `agent` must be null or a live agent
+pub unsafe extern "C" fn dict_set_lazy_load(agent: *mut AgentHandle, enabled: bool) {
+  error::catch_errors("dict_set_lazy_load", (), || {
+    to_agent(agent)?.set_lazy_load(enabled);
+    Ok(())
+  })
+}
+
+#[no_mangle]
+/// candidate: candidate as shown such as 悔
+///
+/// returns a new result set of the readings of the candidate.
+/// results_get_okuri_string returns the okuri of each reading. null is returned on error
+///
+/// # Safety
+/// `agent` must be null or a live agent.
+/// `candidate` must be null or a null-terminated string
+pub unsafe extern "C" fn dict_reverse_look_up(
+  agent: *mut AgentHandle,
+  candidate: *const c_char,
+) -> *mut LookUpResults {
+  error::catch_errors("dict_reverse_look_up", ptr::null_mut(), || {
+    let candidate = to_str(candidate)?;
+    Ok(Box::into_raw(Box::new(to_agent(agent)?.reverse_look_up(candidate))))
+  })
+}
+
+#[no_mangle]
+/// results: result set the candidate is selected from
+///
+/// # Safety
+/// `agent` must be null or a live agent and
+/// `results` must be null or a live result set.
+/// the strings must be null or null-terminated
+pub unsafe extern "C" fn dict_commit_candidate(
+  agent: *mut AgentHandle,
+  results: *mut LookUpResults,
+  reading: *const c_char,
+  okuri: *const c_char,
+  candidate: *const c_char,
+) {
+  error::catch_errors("dict_commit_candidate", (), || {
+    let reading = to_chars(reading)?;
+    let (ac_kana, okuri) = to_okuri(okuri)?;
+    to_agent(agent)?.commit(to_results(results)?, reading, &ac_kana, &okuri, to_str(candidate)?);
+    Ok(())
+  })
+}
+
+#[no_mangle]
+/// # Safety
+/// `agent` must be null or a live agent.
+/// the strings must be null or null-terminated
+pub unsafe extern "C" fn dict_register_word(
+  agent: *mut AgentHandle,
+  reading: *const c_char,
+  okuri: *const c_char,
+  word: *const c_char,
+) -> UserDictResult {
+  error::catch_errors("dict_register_word", UserDictResult::Failed, || {
+    let reading = to_chars(reading)?;
+    let (ac_kana, okuri) = to_okuri(okuri)?;
+    Ok(to_agent(agent)?.register(&reading, &ac_kana, &okuri, to_str(word)?))
+  })
+}
+
+#[no_mangle]
+/// results: result set the candidate is selected from
+///
+/// # Safety
+/// `agent` must be null or a live agent and
+/// `results` must be null or a live result set.
+/// the strings must be null or null-terminated
+pub unsafe extern "C" fn dict_purge_candidate(
+  agent: *mut AgentHandle,
+  results: *mut LookUpResults,
+  reading: *const c_char,
+  okuri: *const c_char,
+  candidate: *const c_char,
+) -> UserDictResult {
+  error::catch_errors("dict_purge_candidate", UserDictResult::Failed, || {
+    let reading = to_chars(reading)?;
+    let (ac_kana, _) = to_okuri(okuri)?;
+    Ok(to_agent(agent)?.purge(to_results(results)?, reading, &ac_kana, to_str(candidate)?))
+  })
+}
+
+#[no_mangle]
+/// same as save_user_dict but for the agent.
+/// the changes are also saved when the agent is destroyed
+///
+/// # Safety
+/// `agent` must be null or a live agent
+pub unsafe extern "C" fn dict_save_user_dict(agent: *mut AgentHandle) -> UserDictResult {
+  error::catch_errors("dict_save_user_dict", UserDictResult::Failed, || {
+    Ok(to_agent(agent)?.save_user_dict())
+  })
+}
+
+#[no_mangle]
+/// # Safety
+/// `agent` must be null or a live agent
+pub unsafe extern "C" fn dict_get_num_warnings(agent: *mut AgentHandle) -> size_t {
+  error::catch_errors("dict_get_num_warnings", 0, || Ok(lock(&to_agent(agent)?.warnings).len()))
+}
+
+#[no_mangle]
+/// # Safety
+/// `agent` must be null or a live agent.
+/// the buffers must be as described for get_warnings
+pub unsafe extern "C" fn dict_get_warnings(
+  agent: *mut AgentHandle,
+  warnings: *mut *mut c_char,
+  buf_size: size_t,
+  offset: size_t,
+  num_warnings: *mut size_t,
+) {
+  error::catch_errors("dict_get_warnings", (), || {
+    let strs = to_agent(agent)?.warnings();
+    let strs: Vec<&str> = strs.iter().map(|x| x.as_str()).collect();
+    copy_to_bufs(&strs, warnings, buf_size, offset, num_warnings)
+  })
+}
+
+#[no_mangle]
+/// # Safety
+/// `results` must be null or a live result set
+pub unsafe extern "C" fn results_count(results: *mut LookUpResults) -> size_t {
+  error::catch_errors("results_count", 0, || Ok(to_results(results)?.results.len()))
+}
+
+#[no_mangle]
+/// same as get_results but for the result set
+///
+/// # Safety
+/// `results` must be null or a live result set.
+/// the buffers must be as described for get_results
+pub unsafe extern "C" fn results_get(
+  results: *mut LookUpResults,
+  bufs: *mut *mut c_char,
+  buf_size: size_t,
+  offset: size_t,
+  num_results: *mut size_t,
+) {
+  error::catch_errors("results_get", (), || {
+    copy_to_bufs(&to_results(results)?.rendered(), bufs, buf_size, offset, num_results)
+  })
+}
+
+#[no_mangle]
+/// same as get_annotations but for the result set
+///
+/// # Safety
+/// `results` must be null or a live result set.
+/// the buffers must be as described for get_results
+pub unsafe extern "C" fn results_get_annotations(
+  results: *mut LookUpResults,
+  bufs: *mut *mut c_char,
+  buf_size: size_t,
+  offset: size_t,
+  num_annotations: *mut size_t,
+) {
+  error::catch_errors("results_get_annotations", (), || {
+    copy_to_bufs(&to_results(results)?.annotations(), bufs, buf_size, offset, num_annotations)
+  })
+}
+
+// returns the string allocated in rust and its length in bytes.
+// null is returned if the index is out of range
+fn to_owned_string(s: Option<&str>, len: *mut size_t) -> *mut c_char {
+  match s {
+    Some(s) => {
+      if !len.is_null() {
+        unsafe { *len = s.len() };
+      }
+      // candidates never contain a null char
+      CString::new(s).unwrap_or_default().into_raw()
+    },
+    None => ptr::null_mut(),
+  }
+}
+
+#[no_mangle]
+/// index: index of the result. results_count tells the range
+/// len: [out] length of the result in bytes w/o the null terminator. can be null
+///
+/// returns the null-terminated result that must be freed by free_string
+///
+/// # Safety
+/// `results` must be null or a live result set.
+/// `len` must be null or writable
+pub unsafe extern "C" fn results_get_string(
+  results: *mut LookUpResults,
+  index: size_t,
+  len: *mut size_t,
+) -> *mut c_char {
+  error::catch_errors("results_get_string", ptr::null_mut(), || {
+    Ok(to_owned_string(to_results(results)?.rendered().get(index).copied(), len))
+  })
+}
+
+#[no_mangle]
+/// same as results_get_string but returns the annotation of the result
+///
+/// # Safety
+/// `results` must be null or a live result set.
+/// `len` must be null or writable
+pub unsafe extern "C" fn results_get_annotation_string(
+  results: *mut LookUpResults,
+  index: size_t,
+  len: *mut size_t,
+) -> *mut c_char {
+  error::catch_errors("results_get_annotation_string", ptr::null_mut(), || {
+    Ok(to_owned_string(to_results(results)?.annotations().get(index).copied(), len))
+  })
+}
+
+#[no_mangle]
+/// same as results_get_string but returns the okuri of the reading returned by
+/// dict_reverse_look_up in the form given to dict_look_up. empty for okuri-nasi
+///
+/// # Safety
+/// `results` must be null or a live result set.
+/// `len` must be null or writable
+pub unsafe extern "C" fn results_get_okuri_string(
+  results: *mut LookUpResults,
+  index: size_t,
+  len: *mut size_t,
+) -> *mut c_char {
+  error::catch_errors("results_get_okuri_string", ptr::null_mut(), || {
+    Ok(to_owned_string(to_results(results)?.okuris().get(index).copied(), len))
+  })
+}
+
+#[no_mangle]
+/// frees a string returned by the results_get_*_string functions
+///
+/// # Safety
+/// `s` must be null or a string returned by a results_get_*_string function.
+/// the string must not be freed twice
+pub unsafe extern "C" fn free_string(s: *mut c_char) {
+  error::catch_errors("free_string", (), || {
+    if !s.is_null() {
+      drop(unsafe { CString::from_raw(s) });
+    }
+    Ok(())
+  })
+}
+
+#[no_mangle]
+/// # Safety
+/// `results` must be null or a live result set. it must not be used afterwards
+pub unsafe extern "C" fn results_destroy(results: *mut LookUpResults) {
+  error::catch_errors("results_destroy", (), || {
+    if !results.is_null() {
+      drop(unsafe { Box::from_raw(results) });
+    }
+    Ok(())
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // taken by the tests that push errors to the queue shared w/ the other tests,
+  // which include builds and user dictionary changes
+  fn lock_error_queue() -> MutexGuard<'static, ()> {
+    error::TEST_QUEUE_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  fn wait_until_built(agent: &DictAgent) -> BuildState {
+    loop {
+      match agent.build_progress().state {
+        BuildState::Ready => return BuildState::Ready,
+        BuildState::Failed => return BuildState::Failed,
+        _ => thread::sleep(std::time::Duration::from_millis(10)),
+      }
+    }
+  }
+
+  #[test]
+  pub fn test_agents_are_independent() {
+    let _lock = lock_error_queue();
+    let dir = std::env::temp_dir();
+    let path_a = dir.join(format!("minskk-{}-agent-a", std::process::id()));
+    let path_b = dir.join(format!("minskk-{}-agent-b", std::process::id()));
+    fs::write(&path_a, "かん /缶/\n").unwrap();
+    fs::write(&path_b, "かん /管/\n").unwrap();
+
+    let agent_a = Arc::new(DictAgent::new());
+    let agent_b = Arc::new(DictAgent::new());
+    assert!(agent_a.build(vec![Some(path_a.clone())]) == vec![BuildResult::Success]);
+    assert!(agent_b.build(vec![Some(path_b.clone()), None]) ==
+      vec![BuildResult::Success, BuildResult::PathMalformed]);
+    assert!(wait_until_built(&agent_a) == BuildState::Ready);
+    assert!(wait_until_built(&agent_b) == BuildState::Ready);
+
+    // result sets should be kept separately
+    let reading = vec!['か', 'ん'];
+    let results_a = agent_a.look_up(&reading, &None, "");
+    let results_b = agent_b.look_up(&reading, &None, "");
+    assert_eq!(results_a.rendered(), vec!["缶"]);
+    assert_eq!(results_b.rendered(), vec!["管"]);
+
+    // rebuilding should replace the dictionaries
+    assert!(agent_a.build(vec![Some(path_b.clone())]) == vec![BuildResult::Success]);
+    assert!(wait_until_built(&agent_a) == BuildState::Ready);
+    assert_eq!(agent_a.look_up(&reading, &None, "").rendered(), vec!["管"]);
+
+    for path in [path_a, path_b] {
+      let mmap = PathBuf::from(format!("{}.mmap", path.display()));
+      fs::remove_file(&path).unwrap();
+      fs::remove_file(&mmap).unwrap();
+    }
+  }
+
+  #[test]
+  pub fn test_complete_skips_purged_readings() {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("minskk-{}-agent-complete", std::process::id()));
+    let user_path = dir.join(format!("minskk-{}-agent-complete-user", std::process::id()));
+    let _ = fs::remove_file(&user_path);
+    fs::write(&path, "かい /会/\nかん /缶/管/\n").unwrap();
+
+    let _lock = lock_error_queue();
+    let agent = Arc::new(DictAgent::new());
+    agent.build(vec![Some(path.clone())]);
+    assert!(wait_until_built(&agent) == BuildState::Ready);
+    agent.load_user_dict(Some(user_path.clone()));
+
+    // the reading should be offered until all of its candidates are purged
+    let user_dict = &agent.user_dict;
+    lock(user_dict).as_mut().unwrap().purge(&['か', 'ん'], &None, "缶", true).unwrap();
+    assert_eq!(agent.complete(&['か'], 10).rendered(), vec!["かい", "かん"]);
+    lock(user_dict).as_mut().unwrap().purge(&['か', 'ん'], &None, "管", true).unwrap();
+    assert_eq!(agent.complete(&['か'], 10).rendered(), vec!["かい"]);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(format!("{}.mmap", path.display())).unwrap();
+    // not to save the changes when the agent is dropped
+    lock(user_dict).take();
+  }
+
+  #[test]
+  pub fn test_rebuild_stale_cache() {
+    let path = std::env::temp_dir().join(format!("minskk-{}-agent-stale", std::process::id()));
+    let mmap = PathBuf::from(format!("{}.mmap", path.display()));
+    fs::write(&path, "かん /缶/\n").unwrap();
+
+    let _lock = lock_error_queue();
+    let agent = Arc::new(DictAgent::new());
+    agent.build(vec![Some(path.clone())]);
+    assert!(wait_until_built(&agent) == BuildState::Ready);
+    assert!(mmap.exists());
+
+    // the cache should be used until the source is modified after it
+    assert!(matches!(get_dict_file_to_load(&path, false), DictFile::Mapped(..)));
+    fs::write(&path, "かん /管/\n").unwrap();
+    let source_modified = fs::metadata(&path).unwrap().modified().unwrap();
+    File::options().write(true).open(&mmap).unwrap()
+      .set_modified(source_modified - Duration::from_secs(10)).unwrap();
+    assert!(matches!(get_dict_file_to_load(&path, false), DictFile::Raw(..)));
+
+    // and rewritten by the rebuild
+    agent.build(vec![Some(path.clone())]);
+    assert!(wait_until_built(&agent) == BuildState::Ready);
+    assert_eq!(agent.look_up(&['か', 'ん'], &None, "").rendered(), vec!["管"]);
+    assert!(matches!(get_dict_file_to_load(&path, false), DictFile::Mapped(..)));
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&mmap).unwrap();
+  }
+
+  // returns the handle of an agent whose thread saves the user dictionary after the delay
+  // and the receiver notified after each save by the thread
+  fn create_agent_w_saver(delay: Duration) -> (*mut AgentHandle, mpsc::Receiver<()>) {
+    let (sender, receiver) = mpsc::channel();
+    let mut agent = DictAgent::new();
+    agent.saver_settings = SaverSettings { delay, on_saved: Some(sender) };
+    (Box::into_raw(Box::new(Arc::new(agent))), receiver)
+  }
+
+  #[test]
+  pub fn test_save_user_dict_later() {
+    let _lock = lock_error_queue();
+    let path = std::env::temp_dir().join(format!("minskk-{}-agent-user-dict", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let reading = CString::new("かん").unwrap();
+    let okuri = CString::new("").unwrap();
+    let look_up_saved = || UserDict::load(&path).unwrap().look_up(&vec!['か', 'ん'], &None)
+      .map(|xs| xs.join("/"));
+
+    unsafe {
+      // the change should be saved by the thread
+      let (agent, saved) = create_agent_w_saver(Duration::ZERO);
+      assert!(dict_load_user_dict(agent, CString::new(path.to_str().unwrap()).unwrap().as_ptr()) ==
+        BuildResult::Success);
+      let word = CString::new("缶").unwrap();
+      assert!(dict_register_word(agent, reading.as_ptr(), okuri.as_ptr(), word.as_ptr()) ==
+        UserDictResult::Success);
+      saved.recv().unwrap();
+      assert_eq!(look_up_saved().as_deref(), Some("缶"));
+      dict_destroy(agent);
+
+      // not until the delay passes
+      let (agent, saved) = create_agent_w_saver(Duration::from_secs(3600));
+      to_agent(agent).unwrap().load_user_dict(Some(path.clone()));
+      let word = CString::new("管").unwrap();
+      dict_register_word(agent, reading.as_ptr(), okuri.as_ptr(), word.as_ptr());
+      assert!(saved.try_recv().is_err());
+      assert_eq!(look_up_saved().as_deref(), Some("缶"));
+
+      // but right away by the explicit save
+      assert!(dict_save_user_dict(agent) == UserDictResult::Success);
+      assert_eq!(look_up_saved().as_deref(), Some("管/缶"));
+
+      // and by destroying the agent
+      let word = CString::new("巻").unwrap();
+      dict_register_word(agent, reading.as_ptr(), okuri.as_ptr(), word.as_ptr());
+      dict_destroy(agent);
+      assert_eq!(look_up_saved().as_deref(), Some("巻/管/缶"));
+    }
+
+    // a file that cannot be read should not be reported as not found
+    while error::pop_error().is_some() {}
+    let agent = DictAgent::new();
+    assert!(agent.load_user_dict(Some(std::env::temp_dir())) == BuildResult::Failed);
+    assert!(error::pop_error().is_some_and(|x| x.code == ErrorCode::UserDictLoadFailed));
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_owned_results() {
+    let _lock = lock_error_queue();
+    let path = std::env::temp_dir().join(format!("minskk-{}-agent-owned", std::process::id()));
+    fs::write(&path, "かんじ /漢字;kanji/幹事/\n").unwrap();
+
+    unsafe {
+      let agent = dict_create();
+      to_agent(agent).unwrap().build(vec![Some(path.clone())]);
+      assert!(wait_until_built(to_agent(agent).unwrap()) == BuildState::Ready);
+
+      let reading = CString::new("かんじ").unwrap();
+      let okuri = CString::new("").unwrap();
+      let results = dict_look_up(agent, reading.as_ptr(), okuri.as_ptr());
+      assert_eq!(results_count(results), 2);
+
+      // multi-byte results should be returned as they are w/ the exact length
+      let mut len: size_t = 0;
+      let s = results_get_string(results, 0, &mut len);
+      assert_eq!(CStr::from_ptr(s).to_str().unwrap(), "漢字");
+      assert_eq!(len, "漢字".len());
+      free_string(s);
+
+      let s = results_get_annotation_string(results, 0, ptr::null_mut());
+      assert_eq!(CStr::from_ptr(s).to_str().unwrap(), "kanji");
+      free_string(s);
+
+      assert!(results_get_string(results, 2, &mut len).is_null());
+
+      results_destroy(results);
+      dict_destroy(agent);
+    }
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(format!("{}.mmap", path.display())).unwrap();
+  }
+
+  #[test]
+  pub fn test_parse_okuri() {
+    assert_eq!(parse_okuri(""), (None, "".to_string()));
+    assert_eq!(parse_okuri(" "), (None, "".to_string()));
+    assert_eq!(parse_okuri("s"), (Some('s'), "".to_string()));
+    assert_eq!(parse_okuri("sし"), (Some('s'), "し".to_string()));
+    assert_eq!(parse_okuri("tっ"), (Some('t'), "っ".to_string()));
+  }
+
+  #[test]
+  pub fn test_invalid_input() {
+    let _lock = lock_error_queue();
+    while error::pop_error().is_some() {}
+
+    // invalid arguments should be reported as errors instead of panicking
+    unsafe {
+      let agent = dict_create();
+      let invalid = CString::new(vec![0xffu8, 0xfe]).unwrap();
+      let okuri = CString::new("").unwrap();
+      assert!(dict_look_up(agent, invalid.as_ptr(), okuri.as_ptr()).is_null());
+      assert!(dict_look_up(ptr::null_mut(), okuri.as_ptr(), okuri.as_ptr()).is_null());
+      assert_eq!(results_count(ptr::null_mut()), 0);
+      assert!(dict_build(agent, ptr::null_mut(), 1, ptr::null_mut()) == BuildResult::Failed);
+
+      let mut message = [0 as c_char; 64];
+      let mut codes = vec![];
+      loop {
+        match get_last_error(message.as_mut_ptr(), message.len()) {
+          ErrorCode::NoError => break,
+          code => codes.push(code),
+        }
+      }
+      assert_eq!(codes, vec![ErrorCode::InvalidInput; 4]);
+
+      // the agent should still be usable
+      let reading = CString::new("かん").unwrap();
+      let results = dict_look_up(agent, reading.as_ptr(), okuri.as_ptr());
+      assert_eq!(results_count(results), 0);
+      results_destroy(results);
+      dict_destroy(agent);
+    }
+  }
+
+  #[test]
+  pub fn test_poisoned_lock() {
+    let agent = Arc::new(DictAgent::new());
+    let poisoning_agent = Arc::clone(&agent);
+    let _ = thread::spawn(move || {
+      let _progress = poisoning_agent.build_progress.lock().unwrap();
+      panic!("poison");
+    }).join();
+
+    assert!(agent.build_progress.is_poisoned());
+    assert!(agent.build_progress().state == BuildState::NotStarted);
+  }
+
+  #[test]
+  pub fn test_lazy_load() {
+    let path = std::env::temp_dir().join(format!("minskk-{}-agent-lazy", std::process::id()));
+    let mmap = PathBuf::from(format!("{}.mmap", path.display()));
+    fs::write(&path, ";; okuri-ari entries.\nくやs /悔/[し/悔/]/\n;; okuri-nasi entries.\nかん /缶/\nかんじ /漢字/\n").unwrap();
+
+    let _lock = lock_error_queue();
+    let agent = Arc::new(DictAgent::new());
+    agent.set_lazy_load(true);
+    agent.build(vec![Some(path.clone())]);
+    assert!(wait_until_built(&agent) == BuildState::Ready);
+
+    // the source should be looked up w/o the cache
+    assert!(matches!(agent.get_dicts().unwrap()[0], SystemDict::Sorted(_)));
+    assert!(!mmap.exists());
+    assert_eq!(agent.look_up(&['く', 'や'], &Some('s'), "し").rendered(), vec!["悔"]);
+    assert_eq!(agent.complete(&['か'], 10).rendered(), vec!["かん", "かんじ"]);
+
+    // an unsorted file should be built in memory instead
+    fs::write(&path, ";; okuri-nasi entries.\nかんじ /漢字/\nかん /缶/\n").unwrap();
+    agent.build(vec![Some(path.clone())]);
+    assert!(wait_until_built(&agent) == BuildState::Ready);
+    assert!(matches!(agent.get_dicts().unwrap()[0], SystemDict::Mapped(_)));
+    assert_eq!(agent.look_up(&['か', 'ん'], &None, "").rendered(), vec!["缶"]);
+    assert!(error::pop_error().is_some_and(|x| x.code == ErrorCode::ReadFailed));
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&mmap).unwrap();
+  }
+
+  #[test]
+  pub fn test_reverse_look_up() {
+    let _lock = lock_error_queue();
+    let path = std::env::temp_dir().join(format!("minskk-{}-agent-reverse", std::process::id()));
+    let mmap = PathBuf::from(format!("{}.mmap", path.display()));
+    fs::write(&path, ";; okuri-ari entries.\nくやs /悔/[し/悔/]/\n;; okuri-nasi entries.\nかいこん /悔恨/\n").unwrap();
+
+    let agent = Arc::new(DictAgent::new());
+    agent.set_reverse_index(true);
+    agent.build(vec![Some(path.clone())]);
+    assert!(wait_until_built(&agent) == BuildState::Ready);
+
+    let results = agent.reverse_look_up("悔");
+    assert_eq!(results.rendered(), vec!["くや"]);
+    assert_eq!(results.okuris(), vec!["sし"]);
+    assert_eq!(agent.reverse_look_up("悔恨").okuris(), vec![""]);
+    assert!(agent.reverse_look_up("後悔").results.is_empty());
+
+    // the index should be cached and used by the dictionary mapped from the cache
+    assert!(MmapDict::open(&mmap).unwrap().has_reverse_index());
+    agent.build(vec![Some(path.clone())]);
+    assert!(wait_until_built(&agent) == BuildState::Ready);
+    assert!(matches!(agent.get_dicts().unwrap()[0], SystemDict::Mapped(_)));
+    assert_eq!(agent.reverse_look_up("悔").rendered(), vec!["くや"]);
+    assert_eq!(agent.look_up(&['く', 'や'], &Some('s'), "し").rendered(), vec!["悔"]);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&mmap).unwrap();
+  }
+}
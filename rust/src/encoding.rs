@@ -0,0 +1,191 @@
+// detection of the encoding of dictionary files.
+// EUC-JIS-2004 is decoded as EUC-JP since encoding_rs doesn't support JIS X 0213.
+// the lines w/ the chars added by JIS X 0213 cannot be decoded and are skipped w/ warnings
+
+use encoding_rs::{Encoding, EUC_JP, SHIFT_JIS, UTF_8};
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum DictEncoding {
+  // encoding of SKK-JISYO.* and ~/.skk-jisyo unless specified
+  #[default]
+  EucJp,
+  EucJis2004,
+  ShiftJis,
+  Utf8,
+}
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+// # of bytes used for the heuristics
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+
+impl DictEncoding {
+  // returns the encoding of the bytes and the length of the BOM to skip.
+  // precedence:
+  // 1. BOM
+  // 2. coding in the header such as ;; -*- coding: utf-8 -*-
+  // 3. heuristics
+  pub fn detect(bytes: &[u8]) -> (Self, usize) {
+    if bytes.starts_with(UTF8_BOM) {
+      return (DictEncoding::Utf8, UTF8_BOM.len());
+    }
+    if let Some(encoding) = DictEncoding::from_header(bytes) {
+      return (encoding, 0);
+    }
+    (DictEncoding::guess(bytes), 0)
+  }
+
+  // emacs looks for the coding in the first 2 lines
+  fn from_header(bytes: &[u8]) -> Option<Self> {
+    for line in bytes.split(|x| *x == b'\n').take(2) {
+      let line = String::from_utf8_lossy(line);
+      let Some(pos) = line.find("coding:") else {
+        continue;
+      };
+      let label = line[pos + "coding:".len()..]
+        .trim_start()
+        .split(|c: char| c.is_whitespace() || c == ';')
+        .next()
+        .unwrap_or("");
+      return DictEncoding::from_label(label);
+    }
+    None
+  }
+
+  fn from_label(label: &str) -> Option<Self> {
+    let label = label.to_ascii_lowercase().replace('_', "-");
+    // drop the eol type such as utf-8-unix
+    let label = ["-unix", "-dos", "-mac"].iter()
+      .find_map(|x| label.strip_suffix(x))
+      .unwrap_or(&label);
+
+    match label {
+      "utf-8" | "utf8" | "prefer-utf-8" => Some(DictEncoding::Utf8),
+      "euc-jp" | "euc-japan" | "japanese-iso-8bit" => Some(DictEncoding::EucJp),
+      "euc-jis-2004" | "euc-jisx0213" => Some(DictEncoding::EucJis2004),
+      "shift-jis" | "sjis" | "cp932" | "japanese-shift-jis" | "japanese-cp932"
+        => Some(DictEncoding::ShiftJis),
+      _ => None,
+    }
+  }
+
+  // valid UTF-8 w/ non-ASCII chars is taken as UTF-8.
+  // otherwise the one that decodes the sample more cleanly is taken out of EUC-JP and Shift_JIS
+  fn guess(bytes: &[u8]) -> Self {
+    let mut sample = &bytes[..usize::min(bytes.len(), SAMPLE_SIZE)];
+    // not to cut a multibyte char in the middle
+    if sample.len() < bytes.len() {
+      if let Some(pos) = sample.iter().rposition(|x| *x == b'\n') {
+        sample = &sample[..pos];
+      }
+    }
+
+    if sample.is_ascii() {
+      return DictEncoding::default();
+    }
+    if std::str::from_utf8(sample).is_ok() {
+      return DictEncoding::Utf8;
+    }
+
+    // half-width katakana are rare in dictionaries
+    // but EUC-JP bytes are often decoded as them in Shift_JIS
+    let score = |encoding: &'static Encoding| {
+      let (s, _) = encoding.decode_without_bom_handling(sample);
+      s.chars()
+        .filter(|c| *c == char::REPLACEMENT_CHARACTER || ('\u{ff61}'..='\u{ff9f}').contains(c))
+        .count()
+    };
+    if score(SHIFT_JIS) < score(EUC_JP) {
+      DictEncoding::ShiftJis
+    } else {
+      DictEncoding::EucJp
+    }
+  }
+
+  fn encoding(&self) -> &'static Encoding {
+    match self {
+      DictEncoding::EucJp | DictEncoding::EucJis2004 => EUC_JP,
+      DictEncoding::ShiftJis => SHIFT_JIS,
+      DictEncoding::Utf8 => UTF_8,
+    }
+  }
+
+  pub fn decode(&self, bytes: &[u8]) -> String {
+    self.encoding().decode_without_bom_handling(bytes).0.into_owned()
+  }
+
+  // None if the bytes are malformed or have chars that cannot be decoded
+  // such as the ones of JIS X 0213 in EUC-JIS-2004
+  pub fn decode_strict(&self, bytes: &[u8]) -> Option<String> {
+    let (s, had_errors) = self.encoding().decode_without_bom_handling(bytes);
+    (!had_errors).then(|| s.into_owned())
+  }
+
+  pub fn encode(&self, s: &str) -> Vec<u8> {
+    self.encoding().encode(s).0.into_owned()
+  }
+
+  // None if the encoding cannot represent some of the chars.
+  // encode replaces them w/ numeric character references such as &#128512;
+  pub fn encode_strict(&self, s: &str) -> Option<Vec<u8>> {
+    let (bytes, _, had_unmappable_chars) = self.encoding().encode(s);
+    (!had_unmappable_chars).then(|| bytes.into_owned())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn test_detect() {
+    let line = "かんじ /漢字/\n";
+
+    let (bytes, _, _) = EUC_JP.encode(line);
+    assert_eq!(DictEncoding::detect(&bytes), (DictEncoding::EucJp, 0));
+
+    let (bytes, _, _) = SHIFT_JIS.encode(line);
+    assert_eq!(DictEncoding::detect(&bytes), (DictEncoding::ShiftJis, 0));
+
+    assert_eq!(DictEncoding::detect(line.as_bytes()), (DictEncoding::Utf8, 0));
+
+    let bytes = [UTF8_BOM, line.as_bytes()].concat();
+    assert_eq!(DictEncoding::detect(&bytes), (DictEncoding::Utf8, 3));
+
+    assert_eq!(DictEncoding::detect(b"a /b/\n"), (DictEncoding::EucJp, 0));
+  }
+
+  #[test]
+  pub fn test_detect_header() {
+    let header = ";; -*- mode: fundamental; coding: utf-8-unix -*-\n";
+    assert_eq!(DictEncoding::detect(header.as_bytes()).0, DictEncoding::Utf8);
+
+    // the header should take precedence over the heuristics
+    let bytes = [b";; -*- coding: euc-jis-2004 -*-\n".as_slice(), "かんじ /漢字/\n".as_bytes()].concat();
+    assert_eq!(DictEncoding::detect(&bytes).0, DictEncoding::EucJis2004);
+
+    let bytes = b";; -*- coding: shift_jis -*-\n";
+    assert_eq!(DictEncoding::detect(bytes).0, DictEncoding::ShiftJis);
+  }
+
+  #[test]
+  pub fn test_decode() {
+    let line = "かんじ /漢字/";
+    for encoding in [DictEncoding::EucJp, DictEncoding::ShiftJis, DictEncoding::Utf8] {
+      assert_eq!(encoding.decode(&encoding.encode(line)), line);
+    }
+  }
+
+  #[test]
+  pub fn test_decode_jis_x_0213() {
+    let encoding = DictEncoding::EucJis2004;
+    assert_eq!(encoding.decode_strict(&EUC_JP.encode("かんじ").0).unwrap(), "かんじ");
+
+    // か゚ (plane 1, row 4) and 𠀋 (plane 2, row 1) are not in EUC-JP
+    for bytes in [b"\xa4\xf7".as_slice(), b"\x8f\xa1\xa2".as_slice()] {
+      assert!(encoding.decode_strict(bytes).is_none());
+      assert!(encoding.decode(bytes).contains(char::REPLACEMENT_CHARACTER));
+    }
+  }
+}
@@ -0,0 +1,130 @@
+// errors reported by the exported functions.
+// they are queued instead of being printed since stdout is not visible inside neovim.
+// the queue is shared by the whole process. the errors of all the agents go to it
+// since builds and saves report them from their own threads w/o a caller to return them to
+
+use once_cell::sync::Lazy;
+use std::{
+  any::Any,
+  collections::VecDeque,
+  panic::{self, AssertUnwindSafe},
+  sync::{Mutex, PoisonError},
+};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+  // returned when the queue is empty
+  NoError = 0,
+  ReadFailed = 1,
+  CacheLoadFailed = 2,
+  CacheSaveFailed = 3,
+  UserDictLoadFailed = 4,
+  UserDictSaveFailed = 5,
+  UserDictNotLoaded = 6,
+  InvalidInput = 7,
+  Internal = 8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgentError {
+  pub code: ErrorCode,
+  pub message: String,
+}
+
+impl AgentError {
+  pub fn new(code: ErrorCode, message: String) -> Self {
+    AgentError { code, message }
+  }
+}
+
+// older errors are dropped once the queue is full not to grow unboundedly
+// while nobody drains it
+const MAX_ERRORS: usize = 100;
+
+static ERRORS: Lazy<Mutex<VecDeque<AgentError>>> =
+  Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// taken by the tests that push to or drain the queue as it is shared by the tests running in parallel
+#[cfg(test)]
+pub static TEST_QUEUE_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn push_error(code: ErrorCode, message: String) {
+  let mut errors = ERRORS.lock().unwrap_or_else(PoisonError::into_inner);
+  if errors.len() == MAX_ERRORS {
+    errors.pop_front();
+  }
+  errors.push_back(AgentError { code, message });
+}
+
+// removes and returns the oldest error
+pub fn pop_error() -> Option<AgentError> {
+  ERRORS.lock().unwrap_or_else(PoisonError::into_inner).pop_front()
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+  if let Some(s) = payload.downcast_ref::<&str>() {
+    s
+  } else if let Some(s) = payload.downcast_ref::<String>() {
+    s
+  } else {
+    "unknown panic"
+  }
+}
+
+// runs the body of an exported function. unwinding into LuaJIT aborts neovim,
+// so errors and panics are queued and default is returned instead
+pub fn catch_errors<T>(
+  name: &str,
+  default: T,
+  f: impl FnOnce() -> Result<T, AgentError>,
+) -> T {
+  match panic::catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(x)) => x,
+    Ok(Err(e)) => {
+      push_error(e.code, format!("{}: {}", name, e.message));
+      default
+    },
+    Err(payload) => {
+      push_error(ErrorCode::Internal, format!("{} panicked: {}", name, panic_message(payload.as_ref())));
+      default
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn test_error_queue() {
+    let _lock = TEST_QUEUE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    while pop_error().is_some() {}
+
+    // errors should be returned in the order they are pushed
+    push_error(ErrorCode::ReadFailed, "a".to_string());
+    push_error(ErrorCode::Internal, "b".to_string());
+    assert_eq!(pop_error().unwrap().message, "a");
+    assert_eq!(pop_error().unwrap().message, "b");
+
+    for i in 0..MAX_ERRORS {
+      push_error(ErrorCode::CacheSaveFailed, i.to_string());
+    }
+    // the oldest should have been dropped
+    let mut messages = vec![];
+    while let Some(error) = pop_error() {
+      messages.push(error.message);
+    }
+    assert_eq!(messages.len(), MAX_ERRORS);
+    assert_eq!(messages[0], "0");
+
+    // errors and panics should be queued w/ the default value returned
+    let x = catch_errors("f", 0, || Err(AgentError::new(ErrorCode::InvalidInput, "a".to_string())));
+    assert_eq!(x, 0);
+    let x = catch_errors("g", 0, || -> Result<i32, AgentError> { panic!("b") });
+    assert_eq!(x, 0);
+    assert_eq!(catch_errors("h", 0, || Ok(1)), 1);
+    assert_eq!(pop_error().unwrap(), AgentError::new(ErrorCode::InvalidInput, "f: a".to_string()));
+    assert_eq!(pop_error().unwrap(), AgentError::new(ErrorCode::Internal, "g panicked: b".to_string()));
+  }
+}
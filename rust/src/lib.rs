@@ -1,3 +1,11 @@
 pub mod dict;
 pub mod dict_agent;
-
+pub mod encoding;
+pub mod error;
+pub mod lisp;
+pub mod mmap_dict;
+pub mod numeric;
+pub mod sorted_dict;
+pub mod system_dict;
+pub mod user_dict;
+pub mod vec_map;
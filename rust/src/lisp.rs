@@ -0,0 +1,292 @@
+// evaluator of the subset of Emacs Lisp used by SKK dictionary candidates
+// such as (concat "DOS\057V") and (skk-current-date)
+
+use libc::{c_char, localtime_r, strftime, time, time_t, tm};
+use std::ffi::CString;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+  Str(String),
+  Int(i64),
+  Symbol(String),
+  List(Vec<Expr>),
+}
+
+const WEEKDAYS: [&str; 7] = ["日", "月", "火", "水", "木", "金", "土"];
+
+// Emacs Lisp functions called by dictionary candidates besides the skk- ones
+const KNOWN_FUNCTIONS: [&str; 6] = [
+  "concat",
+  "current-time-string",
+  "format",
+  "format-time-string",
+  "lambda",
+  "substring",
+];
+
+// same as skk-lisp-prog-p of ddskk but the form should also call a known function
+// so that candidates such as (笑), (株) and (C) are taken as they are
+pub fn is_lisp(candidate: &str) -> bool {
+  let Some(form) = candidate.strip_prefix('(').and_then(|x| x.strip_suffix(')')) else {
+    return false;
+  };
+  if !form.chars().next().is_some_and(|c| c.is_ascii()) {
+    return false;
+  }
+  let head = form.split(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '"')
+    .next()
+    .unwrap_or("");
+  head.starts_with("skk-") || KNOWN_FUNCTIONS.contains(&head)
+}
+
+// returns the string to show for the candidate.
+// None is returned if the candidate is a form that is not supported or broken
+pub fn render(candidate: &str) -> Option<String> {
+  if !is_lisp(candidate) {
+    return Some(candidate.to_owned());
+  }
+  let mut chars = candidate.chars().peekable();
+  let expr = parse(&mut chars)?;
+
+  // trailing chars after the form
+  skip_spaces(&mut chars);
+  if chars.peek().is_some() {
+    return None;
+  }
+  match eval(&expr)? {
+    Expr::Str(s) => Some(s),
+    Expr::Int(n) => Some(n.to_string()),
+    _ => None,
+  }
+}
+
+// returns the candidate as a string that can be written in a dictionary line.
+// '/' and ';' are not allowed there and are written in the form of (concat "\057")
+pub fn quote(candidate: &str) -> String {
+  if !candidate.contains(['/', ';']) {
+    return candidate.to_owned();
+  }
+  let mut s = String::from("(concat \"");
+  for c in candidate.chars() {
+    match c {
+      '/' => s.push_str("\\057"),
+      ';' => s.push_str("\\073"),
+      '"' => s.push_str("\\\""),
+      '\\' => s.push_str("\\\\"),
+      c => s.push(c),
+    }
+  }
+  s.push_str("\")");
+  s
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_spaces(chars: &mut Chars) {
+  while chars.peek().is_some_and(|c| c.is_whitespace()) {
+    chars.next();
+  }
+}
+
+fn parse(chars: &mut Chars) -> Option<Expr> {
+  skip_spaces(chars);
+
+  match chars.peek()? {
+    '(' => {
+      chars.next();
+      let mut exprs = vec![];
+      loop {
+        skip_spaces(chars);
+        if *chars.peek()? == ')' {
+          chars.next();
+          return Some(Expr::List(exprs));
+        }
+        exprs.push(parse(chars)?);
+      }
+    },
+    '"' => {
+      chars.next();
+      parse_str(chars).map(Expr::Str)
+    },
+    ')' => None,
+    _ => {
+      let mut atom = String::new();
+      while let Some(c) = chars.peek() {
+        if c.is_whitespace() || *c == '(' || *c == ')' || *c == '"' {
+          break;
+        }
+        atom.push(*c);
+        chars.next();
+      }
+      match atom.parse::<i64>() {
+        Ok(n) => Some(Expr::Int(n)),
+        Err(_) => Some(Expr::Symbol(atom)),
+      }
+    },
+  }
+}
+
+// parses the string after the opening '"'
+fn parse_str(chars: &mut Chars) -> Option<String> {
+  let mut s = String::new();
+  loop {
+    match chars.next()? {
+      '"' => return Some(s),
+      '\\' => {
+        match chars.next()? {
+          // octal escape such as \057 for '/'
+          c @ '0'..='7' => {
+            let mut code = c.to_digit(8)?;
+            for _ in 0..2 {
+              match chars.peek().and_then(|c| c.to_digit(8)) {
+                Some(d) => {
+                  code = code * 8 + d;
+                  chars.next();
+                },
+                None => break,
+              }
+            }
+            s.push(char::from_u32(code)?);
+          },
+          'n' => s.push('\n'),
+          't' => s.push('\t'),
+          c => s.push(c),
+        }
+      },
+      c => s.push(c),
+    }
+  }
+}
+
+fn eval_to_str(expr: &Expr) -> Option<String> {
+  match eval(expr)? {
+    Expr::Str(s) => Some(s),
+    Expr::Int(n) => Some(n.to_string()),
+    _ => None,
+  }
+}
+
+fn eval(expr: &Expr) -> Option<Expr> {
+  match expr {
+    Expr::Str(_) | Expr::Int(_) => Some(expr.clone()),
+    Expr::Symbol(symbol) => {
+      match symbol.as_str() {
+        "skk-version" => Some(Expr::Str(version())),
+        _ => None,
+      }
+    },
+    Expr::List(exprs) => {
+      let (f, args) = exprs.split_first()?;
+      let f = match f {
+        Expr::Symbol(f) => f.as_str(),
+        _ => return None,
+      };
+      match (f, args.len()) {
+        ("concat", _) => {
+          let mut s = String::new();
+          for arg in args {
+            s.push_str(&eval_to_str(arg)?);
+          }
+          Some(Expr::Str(s))
+        },
+        ("skk-version", 0) => Some(Expr::Str(version())),
+        ("skk-current-date", 0) | ("skk-today", 0) => {
+          let t = local_time()?;
+          Some(Expr::Str(format!(
+            "{}年{}月{}日({})",
+            t.tm_year + 1900,
+            t.tm_mon + 1,
+            t.tm_mday,
+            WEEKDAYS[t.tm_wday as usize % 7],
+          )))
+        },
+        ("current-time-string", 0) => format_time("%a %b %e %H:%M:%S %Y").map(Expr::Str),
+        ("format-time-string", 1) => format_time(&eval_to_str(&args[0])?).map(Expr::Str),
+        _ => None,
+      }
+    },
+  }
+}
+
+fn version() -> String {
+  format!("minskk {}", env!("CARGO_PKG_VERSION"))
+}
+
+fn local_time() -> Option<tm> {
+  unsafe {
+    let now: time_t = time(std::ptr::null_mut());
+    let mut t: tm = std::mem::zeroed();
+    if localtime_r(&now, &mut t).is_null() {
+      None
+    } else {
+      Some(t)
+    }
+  }
+}
+
+fn format_time(format: &str) -> Option<String> {
+  let t = local_time()?;
+  let format = CString::new(format).ok()?;
+  let mut buf = [0u8; 256];
+
+  let len = unsafe {
+    strftime(buf.as_mut_ptr() as *mut c_char, buf.len(), format.as_ptr(), &t)
+  };
+  if len == 0 {
+    return None;
+  }
+  String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn test_render_concat() {
+    assert_eq!(render("漢字").unwrap(), "漢字");
+    assert_eq!(render("(concat \"DOS\\057V\")").unwrap(), "DOS/V");
+    assert_eq!(render("(concat \"a\\073b\" \"c\")").unwrap(), "a;bc");
+    assert_eq!(render("(concat \"\\\"q\\\"\")").unwrap(), "\"q\"");
+    assert_eq!(render("(concat \"x\" (concat \"y\" \"z\"))").unwrap(), "xyz");
+    assert_eq!(render("(concat)").unwrap(), "");
+  }
+
+  #[test]
+  pub fn test_render_builtins() {
+    assert_eq!(render("(skk-version)").unwrap(), version());
+    assert!(render("(skk-current-date)").unwrap().ends_with(")"));
+    assert_eq!(render("(format-time-string \"%%\")").unwrap(), "%");
+    assert!(render("(current-time-string)").is_some());
+  }
+
+  #[test]
+  pub fn test_unsupported_forms() {
+    assert!(render("(skk-gadget-units-conversion \"mile\" 1 \"km\")").is_none());
+    assert!(render("(skk-current-date (lambda (x) x))").is_none());
+    assert!(render("(skk-ignore-dic-word \"缶\")").is_none());
+    assert!(render("(concat \"a)").is_none());
+    assert!(render("(concat (concat \"a\")").is_none());
+    assert!(render("(concat \"a\") (concat \"b\")").is_none());
+  }
+
+  #[test]
+  pub fn test_not_lisp() {
+    // parenthesized words are not forms
+    for s in ["(笑)", "(株)", "(注)", "(C)", "(a b)", "()", "(", "(skk"] {
+      assert!(!is_lisp(s));
+      assert_eq!(render(s).unwrap(), s);
+    }
+    assert!(is_lisp("(concat \"a\")"));
+    assert!(is_lisp("(skk-current-date)"));
+  }
+
+  #[test]
+  pub fn test_quote() {
+    assert_eq!(quote("漢字"), "漢字");
+    assert_eq!(quote("DOS/V"), "(concat \"DOS\\057V\")");
+    for s in ["DOS/V", "a;b", "\"/\\"] {
+      assert_eq!(render(&quote(s)).unwrap(), s);
+    }
+  }
+}
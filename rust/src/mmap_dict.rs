@@ -0,0 +1,449 @@
+// dictionary cache that is memory-mapped and looked up in place w/o deserialization
+// so that lookups are available right after the file is opened.
+//
+// layout. numbers are u32 in little endian:
+//   header: magic, version, parser version, flags, # of okuri-ari entries,
+//     # of okuri-nasi entries, # of reverse entries
+//   okuri-ari entries and okuri-nasi entries: key offset, key length, value offset, value length.
+//     sorted by the key that is the reading followed by the accompanying kana if okuri-ari.
+//     the value is the candidates as in the dictionary such as /帰/返/[る/帰/]/
+//   reverse entries: candidate offset, candidate length, reading offset, reading length,
+//     okuri offset, okuri length. sorted by the candidate, the reading and the okuri
+//   string pool that the offsets point into
+
+use crate::dict::{Candidate, Dict, ReverseEntry, Section, PARSER_VERSION};
+
+use memmap2::Mmap;
+use std::{
+  cmp::Ordering,
+  fs::{self, File},
+  io::{BufWriter, Error, ErrorKind, Result, Write},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: [u8; 4] = *b"MSKM";
+// bump the version whenever the layout changes so that stale files are rebuilt
+const VERSION: u32 = 1;
+const FLAG_REVERSE_INDEX: u32 = 1;
+
+const HEADER_LEN: usize = 28;
+const ENTRY_LEN: usize = 16;
+const REVERSE_ENTRY_LEN: usize = 24;
+
+#[derive(Clone, Copy)]
+enum Table {
+  OkuriAri,
+  OkuriNasi,
+  Reverse,
+}
+
+pub struct MmapDict {
+  mmap: Mmap,
+  flags: u32,
+  num_ari: usize,
+  num_nasi: usize,
+  num_reverse: usize,
+  pool_offset: usize,
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> usize {
+  let mut buf = [0u8; 4];
+  buf.copy_from_slice(&bytes[pos..pos + 4]);
+  u32::from_le_bytes(buf) as usize
+}
+
+// string pool that the entries point into
+struct PoolWriter {
+  pool: Vec<u8>,
+}
+
+impl PoolWriter {
+  // returns the offset and the length of the string.
+  // they are truncated if the pool exceeds u32, which write rejects
+  fn add(&mut self, s: &str) -> [u32; 2] {
+    let offset = self.pool.len() as u32;
+    self.pool.extend_from_slice(s.as_bytes());
+    [offset, s.len() as u32]
+  }
+
+  fn get(&self, [offset, len]: [u32; 2]) -> &[u8] {
+    &self.pool[offset as usize..(offset + len) as usize]
+  }
+}
+
+// temporary file next to the path that is unique to the write.
+// several neovim instances can build the cache of the same dictionary at the same time
+fn tmp_path_for(path: &Path) -> PathBuf {
+  static COUNTER: AtomicUsize = AtomicUsize::new(0);
+  let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.subsec_nanos());
+  let mut tmp_path = path.as_os_str().to_owned();
+  tmp_path.push(format!(
+    ".{}-{}-{}.tmp",
+    std::process::id(),
+    COUNTER.fetch_add(1, AtomicOrdering::Relaxed),
+    nanos,
+  ));
+  PathBuf::from(tmp_path)
+}
+
+// returns the okuri in the form of the accompanying kana followed by the okuri kana
+fn to_okuri_str(entry: &ReverseEntry) -> String {
+  match entry.acc_kana {
+    Some(acc_kana) => format!("{}{}", acc_kana, entry.okuri),
+    None => String::new(),
+  }
+}
+
+fn to_reverse_entry(reading: &str, okuri: &str) -> ReverseEntry {
+  let mut chars = okuri.chars();
+  ReverseEntry {
+    reading: reading.to_owned(),
+    acc_kana: chars.next(),
+    okuri: chars.collect(),
+  }
+}
+
+impl MmapDict {
+  // writes the dictionary to a temporary file first and renames it
+  // so that the file mapped by others is never modified
+  pub fn write(dict: &Dict, path: &PathBuf) -> Result<()> {
+    let mut pool = PoolWriter { pool: vec![] };
+    let mut ari: Vec<[[u32; 2]; 2]> = vec![];
+    let mut nasi: Vec<[[u32; 2]; 2]> = vec![];
+
+    // the entries go to the pool as they are visited w/o the lines of the whole dictionary
+    dict.for_each_entry(&mut |section, key, value| {
+      let entry = [pool.add(key), pool.add(value)];
+      match section {
+        Section::OkuriAri => ari.push(entry),
+        _ => nasi.push(entry),
+      }
+    });
+    // the keys are compared as bytes when looked up
+    ari.sort_by(|a, b| pool.get(a[0]).cmp(pool.get(b[0])));
+    nasi.sort_by(|a, b| pool.get(a[0]).cmp(pool.get(b[0])));
+
+    let mut reverse: Vec<[[u32; 2]; 3]> = vec![];
+    if let Some(index) = dict.reverse_index() {
+      for (candidate, entries) in index {
+        let candidate = pool.add(candidate);
+        for entry in entries {
+          reverse.push([candidate, pool.add(&entry.reading), pool.add(&to_okuri_str(entry))]);
+        }
+      }
+    }
+    reverse.sort_by(|a, b| {
+      let strs = |x: &[[u32; 2]; 3]| x.map(|y| pool.get(y));
+      strs(a).cmp(&strs(b))
+    });
+
+    if u32::try_from(pool.pool.len()).is_err() {
+      return Err(Error::new(ErrorKind::InvalidData, "Dictionary is too large"));
+    }
+    let flags = if dict.has_reverse_index() { FLAG_REVERSE_INDEX } else { 0 };
+    let header = [
+      VERSION,
+      PARSER_VERSION,
+      flags,
+      ari.len() as u32,
+      nasi.len() as u32,
+      reverse.len() as u32,
+    ];
+
+    let tmp_path = tmp_path_for(path);
+    let write_tmp = || -> Result<()> {
+      let mut writer = BufWriter::new(File::create(&tmp_path)?);
+      writer.write_all(&MAGIC)?;
+      let tables = ari.iter().chain(nasi.iter()).flatten()
+        .chain(reverse.iter().flatten())
+        .flatten();
+      for x in header.iter().chain(tables) {
+        writer.write_all(&x.to_le_bytes())?;
+      }
+      writer.write_all(&pool.pool)?;
+      writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+      fs::rename(&tmp_path, path)
+    };
+    write_tmp().inspect_err(|_| {
+      let _ = fs::remove_file(&tmp_path);
+    })
+  }
+
+  pub fn open(path: &Path) -> Result<Self> {
+    let file = File::open(path)?;
+    // the file is replaced by rename instead of being modified in place
+    let mmap = unsafe { Mmap::map(&file)? };
+
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+
+    if mmap.len() < HEADER_LEN || mmap[..4] != MAGIC {
+      return Err(invalid("Not a mapped dictionary".to_string()));
+    }
+    let version = read_u32(&mmap, 4) as u32;
+    if version != VERSION {
+      return Err(invalid(format!("Unsupported mapped dictionary version: {}", version)));
+    }
+    // the cache of the dictionary parsed in another way is rebuilt
+    let parser_version = read_u32(&mmap, 8) as u32;
+    if parser_version != PARSER_VERSION {
+      return Err(invalid(format!("Mapped dictionary was built by another parser: {}", parser_version)));
+    }
+    let flags = read_u32(&mmap, 12) as u32;
+    let num_ari = read_u32(&mmap, 16);
+    let num_nasi = read_u32(&mmap, 20);
+    let num_reverse = read_u32(&mmap, 24);
+
+    // the counts of a broken file can overflow on 32-bit targets
+    let pool_offset = num_ari.checked_add(num_nasi)
+      .and_then(|x| x.checked_mul(ENTRY_LEN))
+      .zip(num_reverse.checked_mul(REVERSE_ENTRY_LEN))
+      .and_then(|(x, y)| x.checked_add(y))
+      .and_then(|x| x.checked_add(HEADER_LEN))
+      .ok_or_else(|| invalid("Mapped dictionary has a broken header".to_string()))?;
+    if mmap.len() < pool_offset {
+      return Err(invalid("Mapped dictionary is truncated".to_string()));
+    }
+
+    Ok(MmapDict { mmap, flags, num_ari, num_nasi, num_reverse, pool_offset })
+  }
+
+  fn table_range(&self, table: Table) -> (usize, usize, usize) {
+    let ari_offset = HEADER_LEN;
+    let nasi_offset = ari_offset + self.num_ari * ENTRY_LEN;
+    let reverse_offset = nasi_offset + self.num_nasi * ENTRY_LEN;
+
+    match table {
+      Table::OkuriAri => (ari_offset, self.num_ari, ENTRY_LEN),
+      Table::OkuriNasi => (nasi_offset, self.num_nasi, ENTRY_LEN),
+      Table::Reverse => (reverse_offset, self.num_reverse, REVERSE_ENTRY_LEN),
+    }
+  }
+
+  fn len(&self, table: Table) -> usize {
+    self.table_range(table).1
+  }
+
+  // returns the bytes of the j-th string of the i-th entry of the table.
+  // an empty slice is returned for a broken file instead of panicking
+  fn bytes_at(&self, table: Table, i: usize, j: usize) -> &[u8] {
+    let (table_offset, _, entry_len) = self.table_range(table);
+    let pos = table_offset + i * entry_len + j * 8;
+    let offset = self.pool_offset.checked_add(read_u32(&self.mmap, pos));
+    let len = read_u32(&self.mmap, pos + 4);
+    offset.and_then(|x| self.mmap.get(x..x.checked_add(len)?)).unwrap_or_default()
+  }
+
+  fn str_at(&self, table: Table, i: usize, j: usize) -> &str {
+    std::str::from_utf8(self.bytes_at(table, i, j)).unwrap_or("")
+  }
+
+  // returns the index of the first entry whose key is not less than the key
+  fn lower_bound(&self, table: Table, key: &str) -> usize {
+    let mut lo = 0;
+    let mut hi = self.len(table);
+
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      match self.bytes_at(table, mid, 0).cmp(key.as_bytes()) {
+        Ordering::Less => lo = mid + 1,
+        _ => hi = mid,
+      }
+    }
+    lo
+  }
+
+  fn find(&self, table: Table, key: &str) -> Option<&str> {
+    let i = self.lower_bound(table, key);
+    (i < self.len(table) && self.bytes_at(table, i, 0) == key.as_bytes())
+      .then(|| self.str_at(table, i, 1))
+  }
+
+  // same as Dict::look_up_okuri_strict
+  pub fn look_up_okuri_strict(
+    &self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+  ) -> Option<Vec<Candidate>> {
+    let mut key: String = readings.iter().collect();
+    let table = match acc_kana {
+      Some(acc_kana) => {
+        key.push(*acc_kana);
+        Table::OkuriAri
+      },
+      None => Table::OkuriNasi,
+    };
+    Some(Dict::parse_candidates_for_okuri(self.find(table, &key)?, acc_kana, okuri))
+  }
+
+  // same as Dict::complete
+  pub fn complete(&self, prefix: &[char], limit: usize) -> Vec<String> {
+    let prefix: String = prefix.iter().collect();
+    let num_prefix_chars = prefix.chars().count();
+    // shorter readings first as in the trie. the table is in the order of the bytes,
+    // so up to limit readings that come first so far are kept while the matches are scanned
+    let mut readings: Vec<(usize, &str)> = vec![];
+
+    for i in self.lower_bound(Table::OkuriNasi, &prefix)..self.len(Table::OkuriNasi) {
+      let reading = self.str_at(Table::OkuriNasi, i, 0);
+      if !reading.starts_with(&prefix) {
+        break;
+      }
+      let reading = (reading.chars().count(), reading);
+      if reading.0 <= num_prefix_chars {
+        continue;
+      }
+      let pos = readings.partition_point(|x| {
+        x.0.cmp(&reading.0).then_with(|| x.1.chars().cmp(reading.1.chars())) == Ordering::Less
+      });
+      if pos < limit {
+        readings.insert(pos, reading);
+        readings.truncate(limit);
+      }
+    }
+    readings.into_iter().map(|x| x.1.to_owned()).collect()
+  }
+
+  pub fn has_reverse_index(&self) -> bool {
+    self.flags & FLAG_REVERSE_INDEX != 0
+  }
+
+  // same as Dict::look_up_readings
+  pub fn look_up_readings(&self, candidate: &str) -> Vec<ReverseEntry> {
+    if self.has_reverse_index() {
+      let mut entries = vec![];
+      for i in self.lower_bound(Table::Reverse, candidate)..self.len(Table::Reverse) {
+        if self.bytes_at(Table::Reverse, i, 0) != candidate.as_bytes() {
+          break;
+        }
+        entries.push(to_reverse_entry(self.str_at(Table::Reverse, i, 1), self.str_at(Table::Reverse, i, 2)));
+      }
+      return entries;
+    }
+
+    // search all the entries w/o the index
+    let mut entries = vec![];
+    for table in [Table::OkuriAri, Table::OkuriNasi] {
+      let is_okuri_ari = matches!(table, Table::OkuriAri);
+      for i in 0..self.len(table) {
+        let (key, value) = (self.str_at(table, i, 0), self.str_at(table, i, 1));
+        Dict::collect_reverse_entries_of_line(key, value, is_okuri_ari, candidate, &mut entries);
+      }
+    }
+    entries.sort();
+    entries.dedup();
+    entries
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn build_dict() -> Dict {
+    let lines: Vec<String> = vec![
+      ";; okuri-ari entries.",
+      "かえr /帰/返/換/[る/帰/返/]/[れ/換;かえる/]/",
+      ";; okuri-nasi entries.",
+      "かん /缶/管/",
+      "かんじ /漢字;kanji/幹事/",
+      "かんじょう /感情/勘定/",
+      "かい /会/回/",
+      "Cyrillic /А/",
+    ].into_iter().map(|x| x.to_string()).collect();
+    Dict::build(&lines).0
+  }
+
+  #[test]
+  pub fn test_look_up_in_place() {
+    let path = std::env::temp_dir().join(format!("minskk-{}-dict.mmap", std::process::id()));
+    let dict = build_dict();
+    MmapDict::write(&dict, &path).unwrap();
+    let mmap_dict = MmapDict::open(&path).unwrap();
+
+    // should be the same as the dictionary it is written from
+    for (readings, acc_kana, okuri) in [
+      ("かえ", Some('r'), "れ"),
+      ("かえ", Some('r'), "る"),
+      ("かえ", Some('r'), ""),
+      ("かん", None, ""),
+      ("かんじ", None, ""),
+      ("Cyrillic", None, ""),
+      ("かえ", None, ""),
+      ("か", None, ""),
+    ] {
+      let readings: Vec<char> = readings.chars().collect();
+      assert_eq!(
+        mmap_dict.look_up_okuri_strict(&readings, &acc_kana, okuri),
+        dict.look_up_okuri_strict(&readings, &acc_kana, okuri),
+      );
+    }
+    let kanjis = mmap_dict.look_up_okuri_strict(&['か', 'ん', 'じ'], &None, "").unwrap();
+    assert_eq!(kanjis[0].annotation.as_deref(), Some("kanji"));
+
+    for prefix in ["か", "かん", "かんじょう", "さ"] {
+      let prefix: Vec<char> = prefix.chars().collect();
+      assert_eq!(mmap_dict.complete(&prefix, 10), dict.complete(&prefix, 10));
+    }
+    for limit in 0..5 {
+      assert_eq!(mmap_dict.complete(&['か'], limit), dict.complete(&['か'], limit));
+    }
+    assert_eq!(mmap_dict.complete(&['か'], 2), vec!["かい", "かん"]);
+
+    // readings should be found w/ and w/o the reverse index
+    assert!(!mmap_dict.has_reverse_index());
+    for candidate in ["帰", "換", "漢字", "А", "無"] {
+      assert_eq!(mmap_dict.look_up_readings(candidate), dict.look_up_readings(candidate));
+    }
+    let mut dict = dict;
+    dict.build_reverse_index();
+    MmapDict::write(&dict, &path).unwrap();
+    let mmap_dict = MmapDict::open(&path).unwrap();
+    assert!(mmap_dict.has_reverse_index());
+    for candidate in ["帰", "換", "漢字", "А", "無"] {
+      assert_eq!(mmap_dict.look_up_readings(candidate), dict.look_up_readings(candidate));
+    }
+
+    // concurrent writes should not share the temporary file
+    assert_ne!(tmp_path_for(&path), tmp_path_for(&path));
+    assert!(!tmp_path_for(&path).exists());
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_reject_broken_files() {
+    let path = std::env::temp_dir().join(format!("minskk-{}-broken.mmap", std::process::id()));
+    MmapDict::write(&build_dict(), &path).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+
+    // truncated tables and other versions should be rejected
+    std::fs::write(&path, &bytes[..HEADER_LEN + 8]).unwrap();
+    assert!(MmapDict::open(&path).is_err());
+
+    for pos in [4, 8] {
+      let mut other_version = bytes.clone();
+      other_version[pos] = 0;
+      std::fs::write(&path, &other_version).unwrap();
+      assert!(MmapDict::open(&path).is_err());
+    }
+
+    // huge counts should not overflow
+    for pos in [16, 24] {
+      let mut broken_header = bytes.clone();
+      broken_header[pos..pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+      std::fs::write(&path, &broken_header).unwrap();
+      assert!(MmapDict::open(&path).is_err());
+    }
+
+    // a broken pool should not panic
+    std::fs::write(&path, &bytes[..bytes.len() - 8]).unwrap();
+    let mmap_dict = MmapDict::open(&path).unwrap();
+    mmap_dict.look_up_okuri_strict(&['か', 'ん'], &None, "");
+    mmap_dict.complete(&['か'], 10);
+
+    std::fs::remove_file(&path).unwrap();
+  }
+}
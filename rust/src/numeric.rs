@@ -0,0 +1,212 @@
+// numeric conversion of dictionary entries such as '#がつ /#1月/#3月/'
+// where #0 to #9 in a candidate are replaced by the number typed in the reading
+
+const KANJI_DIGITS: [char; 10] = ['〇', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+const KANJI_UNITS: [&str; 3] = ["十", "百", "千"];
+const DAIJI_DIGITS: [char; 10] = ['〇', '壱', '弐', '参', '四', '伍', '六', '七', '八', '九'];
+const DAIJI_UNITS: [&str; 3] = ["拾", "百", "阡"];
+const KANJI_BIG_UNITS: [&str; 5] = ["", "万", "億", "兆", "京"];
+const DAIJI_BIG_UNITS: [&str; 5] = ["", "萬", "億", "兆", "京"];
+
+// returns the reading w/ each number replaced by '#' and the numbers in the reading.
+// None is returned if the reading has no number
+pub fn to_numeric_reading(readings: &[char]) -> Option<(Vec<char>, Vec<String>)> {
+  let mut numeric_reading = vec![];
+  let mut numbers: Vec<String> = vec![];
+  let mut in_number = false;
+
+  for c in readings {
+    if c.is_ascii_digit() {
+      if in_number {
+        numbers.last_mut().unwrap().push(*c);
+      } else {
+        numeric_reading.push('#');
+        numbers.push(c.to_string());
+        in_number = true;
+      }
+    } else {
+      numeric_reading.push(*c);
+      in_number = false;
+    }
+  }
+  if numbers.is_empty() {
+    None
+  } else {
+    Some((numeric_reading, numbers))
+  }
+}
+
+// replaces #0 to #9 in the candidate w/ the numbers in order.
+// look_up is used for #4 that converts the number itself as a reading.
+// None is returned if the candidate cannot be rendered w/ the numbers
+pub fn render(
+  candidate: &str,
+  numbers: &[String],
+  look_up: &dyn Fn(&str) -> Option<String>,
+) -> Option<String> {
+  let mut s = String::new();
+  let mut numbers = numbers.iter();
+  let mut chars = candidate.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match (c, chars.peek().and_then(|x| x.to_digit(10))) {
+      ('#', Some(num_type)) => {
+        chars.next();
+        let number = numbers.next()?;
+        s.push_str(&convert(number, num_type, look_up)?);
+      },
+      (c, _) => s.push(c),
+    }
+  }
+  Some(s)
+}
+
+fn convert(
+  number: &str,
+  num_type: u32,
+  look_up: &dyn Fn(&str) -> Option<String>,
+) -> Option<String> {
+  let number = number.trim_start_matches('0');
+  let number = if number.is_empty() { "0" } else { number };
+
+  match num_type {
+    0 => Some(number.to_owned()),
+    1 => Some(to_full_width(number)),
+    2 => Some(number.chars().map(|c| KANJI_DIGITS[digit(c)]).collect()),
+    3 => to_positional(number, &KANJI_DIGITS, &KANJI_UNITS, &KANJI_BIG_UNITS, false),
+    4 => look_up(number),
+    5 => to_positional(number, &DAIJI_DIGITS, &DAIJI_UNITS, &DAIJI_BIG_UNITS, true),
+    8 => Some(to_comma_separated(number)),
+    9 => to_shogi(number),
+    _ => None,
+  }
+}
+
+fn digit(c: char) -> usize {
+  c.to_digit(10).unwrap() as usize
+}
+
+fn to_full_width(number: &str) -> String {
+  number.chars()
+    .map(|c| char::from_u32('０' as u32 + digit(c) as u32).unwrap())
+    .collect()
+}
+
+// e.g. 1234 -> 千二百三十四
+fn to_positional(
+  number: &str,
+  digits: &[char; 10],
+  units: &[&str; 3],
+  big_units: &[&str; 5],
+  keeps_one: bool,
+) -> Option<String> {
+  if number == "0" {
+    return Some(digits[0].to_string());
+  }
+  let chars: Vec<char> = number.chars().collect();
+  let num_groups = chars.len().div_ceil(4);
+  if num_groups > big_units.len() {
+    return None;
+  }
+
+  let mut s = String::new();
+
+  // convert every 4 digits from the highest
+  for group in (0..num_groups).rev() {
+    let end = chars.len() - group * 4;
+    let start = end.saturating_sub(4);
+    let group_chars = &chars[start..end];
+
+    let mut group_s = String::new();
+    for (i, c) in group_chars.iter().enumerate() {
+      let d = digit(*c);
+      let pos = group_chars.len() - i - 1;
+      if d == 0 {
+        continue;
+      }
+      if pos == 0 || d != 1 || keeps_one {
+        group_s.push(digits[d]);
+      }
+      if pos > 0 {
+        group_s.push_str(units[pos - 1]);
+      }
+    }
+    if !group_s.is_empty() {
+      s.push_str(&group_s);
+      s.push_str(big_units[group]);
+    }
+  }
+  Some(s)
+}
+
+// e.g. 1234567 -> 1,234,567
+fn to_comma_separated(number: &str) -> String {
+  let mut s = String::new();
+  for (i, c) in number.chars().enumerate() {
+    if i > 0 && (number.len() - i).is_multiple_of(3) {
+      s.push(',');
+    }
+    s.push(c);
+  }
+  s
+}
+
+// e.g. 34 -> ３四
+fn to_shogi(number: &str) -> Option<String> {
+  let chars: Vec<char> = number.chars().collect();
+  if chars.len() != 2 {
+    return None;
+  }
+  Some(format!("{}{}", to_full_width(&chars[0].to_string()), KANJI_DIGITS[digit(chars[1])]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn no_look_up(_: &str) -> Option<String> {
+    None
+  }
+
+  fn render_num(candidate: &str, number: &str) -> Option<String> {
+    render(candidate, &[number.to_string()], &no_look_up)
+  }
+
+  #[test]
+  pub fn test_to_numeric_reading() {
+    let readings: Vec<char> = "12がつ3にち".chars().collect();
+    let (reading, numbers) = to_numeric_reading(&readings).unwrap();
+    assert_eq!(reading, "#がつ#にち".chars().collect::<Vec<char>>());
+    assert_eq!(numbers, vec!["12", "3"]);
+
+    assert!(to_numeric_reading(&['が', 'つ']).is_none());
+  }
+
+  #[test]
+  pub fn test_render() {
+    assert_eq!(render_num("#0月", "12").unwrap(), "12月");
+    assert_eq!(render_num("#1月", "12").unwrap(), "１２月");
+    assert_eq!(render_num("#2", "1024").unwrap(), "一〇二四");
+    assert_eq!(render_num("#3", "1024").unwrap(), "千二十四");
+    assert_eq!(render_num("#3", "11").unwrap(), "十一");
+    assert_eq!(render_num("#3", "0").unwrap(), "〇");
+    assert_eq!(render_num("#3円", "120000305").unwrap(), "一億二千万三百五円");
+    assert_eq!(render_num("#5", "1024").unwrap(), "壱阡弐拾四");
+    assert_eq!(render_num("#5", "10000").unwrap(), "壱萬");
+    assert_eq!(render_num("#8", "1234567").unwrap(), "1,234,567");
+    assert_eq!(render_num("#8", "123").unwrap(), "123");
+    assert_eq!(render_num("#9", "34").unwrap(), "３四");
+    assert!(render_num("#9", "345").is_none());
+    assert_eq!(render_num("月", "12").unwrap(), "月");
+
+    // numbers should be used in order
+    let numbers = vec!["12".to_string(), "3".to_string()];
+    assert_eq!(render("#0月#1日", &numbers, &no_look_up).unwrap(), "12月３日");
+    assert!(render("#0月#1日#2", &numbers, &no_look_up).is_none());
+
+    // #4 should convert the number itself
+    let look_up = |x: &str| if x == "1" { Some("壱".to_string()) } else { None };
+    assert_eq!(render("#4番", &["1".to_string()], &look_up).unwrap(), "壱番");
+    assert!(render("#4番", &["2".to_string()], &look_up).is_none());
+  }
+}
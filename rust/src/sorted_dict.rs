@@ -0,0 +1,455 @@
+// dictionary looked up in the sorted source file as ddskk does.
+// only the byte ranges of the sections are kept in memory
+// and the line of the reading is found by binary search over the bytes of the section.
+//
+// SKK-JISYO files list the okuri-ari entries in the descending order
+// and the okuri-nasi entries in the ascending order of the readings
+// compared as bytes in the encoding of the file.
+//
+// the file is read w/ pread instead of being mapped since the source file can be edited
+// while it is used and a mapped file truncated by that crashes neovim w/ SIGBUS.
+// such lookups just fail or return the lines at the positions
+
+use crate::{
+  dict::{Candidate, Dict, ReverseEntry, Section},
+  encoding::{self, DictEncoding},
+};
+
+use std::{
+  fs::File,
+  io::{BufRead, BufReader, Error, ErrorKind, Read, Result},
+  ops::Range,
+  os::unix::fs::FileExt,
+  path::Path,
+};
+
+// # of the lines of each section checked on open. checking all of them takes as long as parsing
+const NUM_SORT_SAMPLES: u64 = 64;
+
+pub struct SortedDict {
+  file: File,
+  encoding: DictEncoding,
+  okuri_ari: Range<u64>,
+  okuri_nasi: Range<u64>,
+}
+
+// reads the range of the file w/o the file offset shared by the lookups in parallel
+struct RangeReader<'a> {
+  file: &'a File,
+  pos: u64,
+  end: u64,
+}
+
+impl Read for RangeReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    let len = u64::min(buf.len() as u64, self.end.saturating_sub(self.pos)) as usize;
+    let n = self.file.read_at(&mut buf[..len], self.pos)?;
+    self.pos += n as u64;
+    Ok(n)
+  }
+}
+
+// returns the reading and the candidates of the line
+fn split_line(line: &[u8]) -> (&[u8], &[u8]) {
+  match line.iter().position(|x| *x == b' ' || *x == b'\t') {
+    Some(pos) => (&line[..pos], &line[pos + 1..]),
+    None => (line, &[]),
+  }
+}
+
+// returns the line w/o the line break or None if it is a comment or a blank line.
+// markers and comments are ASCII in any of the encodings
+fn to_entry(line: &[u8]) -> Option<&[u8]> {
+  let line = line.strip_suffix(b"\n").unwrap_or(line);
+  // drop CR of CRLF
+  let line = line.strip_suffix(b"\r").unwrap_or(line);
+  if line.starts_with(b";;") || line.iter().all(|x| x.is_ascii_whitespace()) {
+    None
+  } else {
+    Some(line)
+  }
+}
+
+// whether the key comes before the other in the section
+fn is_before(section: Section, key: &[u8], other: &[u8]) -> bool {
+  match section {
+    Section::OkuriAri => key > other,
+    _ => key < other,
+  }
+}
+
+impl SortedDict {
+  // fails if the file lacks the section markers or the sampled lines are not sorted
+  // since the entries cannot be found by binary search
+  pub fn open(path: &Path) -> Result<Self> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+    let mut sample = vec![];
+    RangeReader { file: &file, pos: 0, end: len }
+      .take(encoding::SAMPLE_SIZE as u64)
+      .read_to_end(&mut sample)?;
+    // not to cut a multibyte char in the middle
+    if (sample.len() as u64) < len {
+      if let Some(pos) = sample.iter().rposition(|x| *x == b'\n') {
+        sample.truncate(pos + 1);
+      }
+    }
+    let (encoding, bom_len) = DictEncoding::detect(&sample);
+
+    // the markers are searched as ddskk does. the okuri-nasi section lasts until the end
+    // and takes most of the file, so the search stops at its marker
+    let mut markers = vec![];
+    let mut reader = BufReader::new(RangeReader { file: &file, pos: bom_len as u64, end: len });
+    let mut pos = bom_len as u64;
+    let mut line = vec![];
+    loop {
+      line.clear();
+      let n = reader.read_until(b'\n', &mut line)? as u64;
+      if n == 0 {
+        break;
+      }
+      if line.starts_with(b";;") {
+        if let Some(section) = Section::from_marker(&String::from_utf8_lossy(&line)) {
+          markers.push((section, pos, pos + n));
+          if markers.iter().any(|x| x.0 == Section::OkuriAri) && section == Section::OkuriNasi {
+            break;
+          }
+        }
+      }
+      pos += n;
+    }
+    if markers.is_empty() {
+      return Err(invalid("No section markers"));
+    }
+
+    // each section lasts until the next marker
+    let mut dict = SortedDict { file, encoding, okuri_ari: 0..0, okuri_nasi: 0..0 };
+    for (i, (section, _, start)) in markers.iter().enumerate() {
+      let end = markers.get(i + 1).map_or(len, |x| x.1);
+      match section {
+        Section::OkuriAri => dict.okuri_ari = *start..end,
+        _ => dict.okuri_nasi = *start..end,
+      }
+    }
+
+    for section in [Section::OkuriAri, Section::OkuriNasi] {
+      if !dict.is_sorted_sample(section)? {
+        return Err(invalid("Dictionary is not sorted"));
+      }
+    }
+    Ok(dict)
+  }
+
+  fn range(&self, section: Section) -> Range<u64> {
+    match section {
+      Section::OkuriAri => self.okuri_ari.clone(),
+      _ => self.okuri_nasi.clone(),
+    }
+  }
+
+  fn reader(&self, pos: u64, end: u64) -> BufReader<RangeReader<'_>> {
+    BufReader::new(RangeReader { file: &self.file, pos, end })
+  }
+
+  // returns the start of the first line at or after the position in the range
+  fn line_start(&self, pos: u64, range: &Range<u64>) -> Result<u64> {
+    if pos <= range.start {
+      return Ok(range.start);
+    }
+    // the line break before the position ends the previous line
+    let n = self.reader(pos - 1, range.end).skip_until(b'\n')?;
+    Ok(pos - 1 + n as u64)
+  }
+
+  // returns the first entry line from the line start until the end along w/ its start
+  // and the start of the next line
+  fn entry_from(&self, start: u64, end: u64) -> Result<Option<(u64, Vec<u8>, u64)>> {
+    let mut reader = self.reader(start, end);
+    let mut start = start;
+    let mut line = vec![];
+    loop {
+      line.clear();
+      let n = reader.read_until(b'\n', &mut line)? as u64;
+      if n == 0 {
+        return Ok(None);
+      }
+      if let Some(entry) = to_entry(&line) {
+        return Ok(Some((start, entry.to_vec(), start + n)));
+      }
+      start += n;
+    }
+  }
+
+  // calls f w/ each entry line from the line start until f returns false
+  fn for_each_entry(&self, start: u64, end: u64, mut f: impl FnMut(&[u8]) -> bool) -> Result<()> {
+    let mut reader = self.reader(start, end);
+    let mut line = vec![];
+    loop {
+      line.clear();
+      if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(());
+      }
+      if let Some(entry) = to_entry(&line) {
+        if !f(entry) {
+          return Ok(());
+        }
+      }
+    }
+  }
+
+  // checks the order of the lines at the evenly spaced positions of the section.
+  // all the lines are checked for small sections
+  fn is_sorted_sample(&self, section: Section) -> Result<bool> {
+    let range = self.range(section);
+    let mut prev: Option<(u64, Vec<u8>)> = None;
+
+    for i in 0..=NUM_SORT_SAMPLES {
+      let pos = range.start + (range.end - range.start) * i / NUM_SORT_SAMPLES;
+      let start = self.line_start(pos, &range)?;
+      let Some((start, line, _)) = self.entry_from(start, range.end)? else {
+        break;
+      };
+      let key = split_line(&line).0.to_vec();
+      if let Some((prev_start, prev_key)) = &prev {
+        if *prev_start == start {
+          continue;
+        }
+        if !is_before(section, prev_key, &key) {
+          return Ok(false);
+        }
+      }
+      prev = Some((start, key));
+    }
+    Ok(true)
+  }
+
+  // returns the first entry line of the section whose key doesn't come before the key
+  // along w/ its start
+  fn lower_bound(&self, section: Section, key: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+    let range = self.range(section);
+    // lo is a line start. the entries starting before lo come before the key
+    // and the ones starting at or after hi don't
+    let (mut lo, mut hi) = (range.start, range.end);
+
+    while lo < hi {
+      let mid = lo + (hi - lo) / 2;
+      let start = self.line_start(mid, &range)?;
+      match self.entry_from(start, range.end)? {
+        Some((start, line, next)) if start < hi => {
+          if is_before(section, split_line(&line).0, key) {
+            lo = next;
+          } else {
+            hi = start;
+          }
+        },
+        // no entry starts between mid and hi
+        _ => hi = mid,
+      }
+    }
+    Ok(self.entry_from(lo, range.end)?.map(|(start, line, _)| (start, line)))
+  }
+
+  // returns the decoded candidates of the key.
+  // read errors are taken as not found as the file may have been modified
+  fn find(&self, section: Section, key: &str) -> Option<String> {
+    let key = self.encoding.encode(key);
+    let (_, line) = self.lower_bound(section, &key).ok()??;
+
+    let (found, value) = split_line(&line);
+    (found == key.as_slice()).then(|| self.encoding.decode(value))
+  }
+
+  // same as Dict::look_up_okuri_strict
+  pub fn look_up_okuri_strict(
+    &self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+  ) -> Option<Vec<Candidate>> {
+    let mut key: String = readings.iter().collect();
+    let section = match acc_kana {
+      Some(acc_kana) => {
+        key.push(*acc_kana);
+        Section::OkuriAri
+      },
+      None => Section::OkuriNasi,
+    };
+    let value = self.find(section, &key)?;
+    Some(Dict::parse_candidates_for_okuri(value.trim(), acc_kana, okuri))
+  }
+
+  // same as Dict::complete except that the readings come in the order of the file
+  // instead of shorter ones first. a short prefix matches too many lines
+  // to read all of them on every completion, so the search stops at the limit
+  pub fn complete(&self, prefix: &[char], limit: usize) -> Vec<String> {
+    let prefix: String = prefix.iter().collect();
+    let encoded_prefix = self.encoding.encode(&prefix);
+    let mut readings: Vec<String> = vec![];
+    if limit == 0 {
+      return readings;
+    }
+
+    let range = self.range(Section::OkuriNasi);
+    if let Ok(Some((start, _))) = self.lower_bound(Section::OkuriNasi, &encoded_prefix) {
+      let _ = self.for_each_entry(start, range.end, |line| {
+        let key = split_line(line).0;
+        if !key.starts_with(&encoded_prefix) {
+          return false;
+        }
+        // the prefix itself is not a completion
+        if key.len() > encoded_prefix.len() {
+          let reading = self.encoding.decode(key);
+          if !readings.contains(&reading) {
+            readings.push(reading);
+          }
+        }
+        readings.len() < limit
+      });
+    }
+    readings
+  }
+
+  // same as Dict::look_up_readings. all the entries are searched
+  pub fn look_up_readings(&self, candidate: &str) -> Vec<ReverseEntry> {
+    let mut entries = vec![];
+    for (section, is_okuri_ari) in [(Section::OkuriAri, true), (Section::OkuriNasi, false)] {
+      let range = self.range(section);
+      let _ = self.for_each_entry(range.start, range.end, |line| {
+        let (key, value) = split_line(line);
+        let key = self.encoding.decode(key);
+        let value = self.encoding.decode(value);
+        Dict::collect_reverse_entries_of_line(&key, value.trim(), is_okuri_ari, candidate, &mut entries);
+        true
+      });
+    }
+    entries.sort();
+    entries.dedup();
+    entries
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use encoding_rs::EUC_JP;
+
+  const LINES: [&str; 9] = [
+    ";; -*- coding: euc-jp -*-",
+    ";; okuri-ari entries.",
+    "かえr /帰/返/換/[る/帰/返/]/[れ/換;かえる/]/",
+    "あつk /厚/熱/",
+    ";; okuri-nasi entries.",
+    "Cyrillic /А/",
+    "かい /会/回/",
+    "かん /缶/管/",
+    "かんじ /漢字;kanji/幹事/",
+  ];
+
+  fn write_dict(name: &str, lines: &[&str]) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("minskk-{}-{}", std::process::id(), name));
+    let text = lines.join("\n") + "\n";
+    let (bytes, _, _) = EUC_JP.encode(&text);
+    std::fs::write(&path, bytes).unwrap();
+    path
+  }
+
+  #[test]
+  pub fn test_look_up_in_source() {
+    let path = write_dict("sorted", &LINES);
+    let sorted_dict = SortedDict::open(&path).unwrap();
+    let lines: Vec<String> = LINES.iter().map(|x| x.to_string()).collect();
+    let dict = Dict::build(&lines).0;
+
+    // should be the same as the dictionary built from the file
+    for (readings, acc_kana, okuri) in [
+      ("かえ", Some('r'), "れ"),
+      ("かえ", Some('r'), "る"),
+      ("あつ", Some('k'), ""),
+      ("かん", None, ""),
+      ("かんじ", None, ""),
+      ("Cyrillic", None, ""),
+      ("かえ", None, ""),
+      ("か", None, ""),
+      ("んん", None, ""),
+    ] {
+      let readings: Vec<char> = readings.chars().collect();
+      assert_eq!(
+        sorted_dict.look_up_okuri_strict(&readings, &acc_kana, okuri),
+        dict.look_up_okuri_strict(&readings, &acc_kana, okuri),
+      );
+    }
+    for prefix in ["か", "かん", "さ"] {
+      let prefix: Vec<char> = prefix.chars().collect();
+      assert_eq!(sorted_dict.complete(&prefix, 10), dict.complete(&prefix, 10));
+    }
+    for candidate in ["帰", "換", "漢字", "А", "無"] {
+      assert_eq!(sorted_dict.look_up_readings(candidate), dict.look_up_readings(candidate));
+    }
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_binary_search() {
+    // long enough to take several probes w/ comments and CRLF in the sections
+    let mut lines = vec![";; okuri-ari entries.".to_string()];
+    lines.extend((0..500).rev().map(|i| format!("か{:04}k /書{}/\r", i, i)));
+    lines.push(";; okuri-nasi entries.".to_string());
+    for i in 0..500 {
+      lines.push(format!("か{:04} /缶{}/", i, i));
+      if i % 100 == 0 {
+        lines.push(";; comment".to_string());
+      }
+    }
+    let lines: Vec<&str> = lines.iter().map(|x| x.as_str()).collect();
+    let path = write_dict("binary-search", &lines);
+    let sorted_dict = SortedDict::open(&path).unwrap();
+
+    for i in [0, 1, 99, 100, 101, 250, 498, 499] {
+      let readings: Vec<char> = format!("か{:04}", i).chars().collect();
+      let kanjis = sorted_dict.look_up_okuri_strict(&readings, &None, "").unwrap();
+      assert_eq!(kanjis, vec![Candidate::new(&format!("缶{}", i))]);
+      let kanjis = sorted_dict.look_up_okuri_strict(&readings, &Some('k'), "").unwrap();
+      assert_eq!(kanjis, vec![Candidate::new(&format!("書{}", i))]);
+    }
+    assert!(sorted_dict.look_up_okuri_strict(&['か', '5'], &None, "").is_none());
+    assert_eq!(sorted_dict.complete(&['か', '0', '4', '9'], 3), vec!["か0490", "か0491", "か0492"]);
+    assert_eq!(sorted_dict.complete(&['か'], 2), vec!["か0000", "か0001"]);
+    assert!(sorted_dict.complete(&['か'], 0).is_empty());
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_truncated_file() {
+    let path = write_dict("truncated", &LINES);
+    let sorted_dict = SortedDict::open(&path).unwrap();
+
+    // lookups should fail instead of crashing as w/ a mapped file
+    std::fs::write(&path, "").unwrap();
+    assert!(sorted_dict.look_up_okuri_strict(&['か', 'ん'], &None, "").is_none());
+    assert!(sorted_dict.complete(&['か'], 10).is_empty());
+    assert!(sorted_dict.look_up_readings("缶").is_empty());
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_reject_unsorted_files() {
+    let mut lines = LINES;
+    lines.swap(6, 7);
+    let path = write_dict("unsorted", &lines);
+    assert!(SortedDict::open(&path).is_err());
+
+    // the section of the entries is unknown w/o the markers
+    let path_no_marker = write_dict("no-marker", &["かん /缶/管/"]);
+    assert!(SortedDict::open(&path_no_marker).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&path_no_marker).unwrap();
+  }
+}
@@ -0,0 +1,96 @@
+use crate::{
+  dict::{Candidate, Dict, ReverseEntry},
+  mmap_dict::MmapDict,
+  sorted_dict::SortedDict,
+};
+
+// system dictionary that lookups go through.
+// built in memory from the source, memory-mapped from the cache
+// or looked up in the sorted source
+pub enum SystemDict {
+  Tree(Dict),
+  Mapped(MmapDict),
+  Sorted(SortedDict),
+}
+
+impl SystemDict {
+  pub fn look_up_okuri_strict(
+    &self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+  ) -> Option<Vec<Candidate>> {
+    match self {
+      SystemDict::Tree(dict) => dict.look_up_okuri_strict(readings, acc_kana, okuri),
+      SystemDict::Mapped(dict) => dict.look_up_okuri_strict(readings, acc_kana, okuri),
+      SystemDict::Sorted(dict) => dict.look_up_okuri_strict(readings, acc_kana, okuri),
+    }
+  }
+
+  pub fn complete(&self, prefix: &[char], limit: usize) -> Vec<String> {
+    match self {
+      SystemDict::Tree(dict) => dict.complete(prefix, limit),
+      SystemDict::Mapped(dict) => dict.complete(prefix, limit),
+      SystemDict::Sorted(dict) => dict.complete(prefix, limit),
+    }
+  }
+
+  pub fn look_up_readings(&self, candidate: &str) -> Vec<ReverseEntry> {
+    match self {
+      SystemDict::Tree(dict) => dict.look_up_readings(candidate),
+      SystemDict::Mapped(dict) => dict.look_up_readings(candidate),
+      SystemDict::Sorted(dict) => dict.look_up_readings(candidate),
+    }
+  }
+
+  // looks up the dictionaries in the order of priority
+  // and returns the candidates w/o duplicates.
+  // the okuri kana can be empty to look up w/o it
+  pub fn look_up_in_order(
+    dicts: &[SystemDict],
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+  ) -> Option<Vec<Candidate>> {
+    let mut res: Option<Vec<Candidate>> = None;
+
+    for dict in dicts {
+      if let Some(kanjis) = dict.look_up_okuri_strict(readings, acc_kana, okuri) {
+        let res = res.get_or_insert_with(Vec::new);
+        for kanji in &kanjis {
+          Candidate::add_unique(res, kanji);
+        }
+      }
+    }
+    res
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn test_looking_up_multiple_dicts_in_order() {
+    let mut dict_l = Dict::new();
+    dict_l.add_dict_file_line("かん /缶/管/巻/").unwrap();
+    dict_l.add_dict_file_line("かk /書/").unwrap();
+    let mut dict_jinmei = Dict::new();
+    dict_jinmei.add_dict_file_line("かん /菅/管/").unwrap();
+    dict_jinmei.add_dict_file_line("さとし /聡/").unwrap();
+    let dicts = vec![SystemDict::Tree(dict_l), SystemDict::Tree(dict_jinmei)];
+
+    let res = SystemDict::look_up_in_order(&dicts, &['か', 'ん'], &None, "").unwrap();
+    assert_eq!(res, vec!["缶", "管", "巻", "菅"]);
+    assert_eq!(res[1].annotation, None);
+
+    let res = SystemDict::look_up_in_order(&dicts, &['さ', 'と', 'し'], &None, "").unwrap();
+    assert_eq!(res, vec!["聡"]);
+
+    let res = SystemDict::look_up_in_order(&dicts, &['か'], &Some('k'), "").unwrap();
+    assert_eq!(res, vec!["書"]);
+
+    assert!(SystemDict::look_up_in_order(&dicts, &['か'], &None, "").is_none());
+    assert!(SystemDict::look_up_in_order(&[], &['か', 'ん'], &None, "").is_none());
+  }
+}
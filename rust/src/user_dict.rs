@@ -0,0 +1,635 @@
+use crate::{
+  dict::{Candidate, Dict, ParseWarning, ReverseEntry},
+  encoding::DictEncoding,
+};
+
+use std::{
+  cmp::Reverse,
+  collections::HashMap,
+  fs::{self, File},
+  io::{BufReader, Error, ErrorKind, Result, Write},
+  path::PathBuf,
+};
+
+// written at the top of the file so that the encoding is not guessed when loaded
+const UTF8_HEADER: &str = ";; -*- coding: utf-8 -*-";
+
+// ddskk-style user dictionary (~/.skk-jisyo) that learns the committed kanjis
+pub struct UserDict {
+  dict: Dict,
+  path: PathBuf,
+  // lines skipped while loading. the dictionary is not saved if any
+  // since they would be dropped from the file
+  warnings: Vec<ParseWarning>,
+  // larger for the keys (readings + accompanying kana) used more recently.
+  // ddskk writes the most recently used entries first in each section
+  stamps: HashMap<String, u64>,
+  next_stamp: u64,
+  // whether the dictionary has changes not saved yet
+  dirty: bool,
+  // whether the file has been written in the encoding. a new file can be
+  // switched to UTF-8 for a word that the default encoding cannot represent
+  has_encoding: bool,
+}
+
+// contents of the user dictionary to write to the file.
+// taken while the dictionary is locked and written after unlocking it
+pub struct Snapshot {
+  path: PathBuf,
+  bytes: Vec<u8>,
+}
+
+impl Snapshot {
+  // writes to a temporary file first not to lose the dictionary
+  // if the write is interrupted
+  pub fn write(&self) -> Result<()> {
+    let mut tmp_path = self.path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    if let Some(dir) = self.path.parent() {
+      if !dir.as_os_str().is_empty() {
+        fs::create_dir_all(dir)?;
+      }
+    }
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&self.bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &self.path)
+  }
+}
+
+// returns the readings + accompanying kana of the line
+fn key_of_line(line: &str) -> &str {
+  line.split([' ', '\t']).next().unwrap_or("")
+}
+
+impl UserDict {
+  // loads the user dictionary from the path.
+  // an empty dictionary is returned if the file doesn't exist yet
+  pub fn load(path: &PathBuf) -> Result<Self> {
+    let bytes = if path.exists() {
+      fs::read(path)?
+    } else {
+      vec![]
+    };
+    let (dict, warnings) = Dict::build_from_reader(&mut BufReader::new(bytes.as_slice()), &mut |_| {})?;
+
+    // the order of the file is kept as the recency of the entries
+    let text = dict.encoding().decode(&bytes);
+    let lines: Vec<&str> = text.lines()
+      .filter(|x| !x.starts_with(";;") && !x.trim().is_empty())
+      .collect();
+    let mut stamps = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+      let key = key_of_line(line.trim_start_matches('\u{feff}'));
+      stamps.entry(key.to_string()).or_insert((lines.len() - i) as u64);
+    }
+
+    Ok(UserDict {
+      dict,
+      path: path.clone(),
+      warnings,
+      stamps,
+      next_stamp: lines.len() as u64 + 1,
+      dirty: false,
+      has_encoding: !bytes.is_empty(),
+    })
+  }
+
+  pub fn warnings(&self) -> &[ParseWarning] {
+    &self.warnings
+  }
+
+  pub fn look_up(&self, readings: &Vec<char>, acc_kana: &Option<char>) -> Option<Vec<&str>> {
+    self.dict.look_up(readings, acc_kana)
+  }
+
+  // readings that only have ignore markers are skipped as they have no candidate to show
+  pub fn complete(&self, prefix: &[char], limit: usize) -> Vec<String> {
+    self.dict.complete_where(prefix, limit, |kanjis| {
+      kanjis.iter().any(|x| UserDict::parse_ignore_marker(&x.kanji).is_none())
+    })
+  }
+
+  pub fn look_up_readings(&self, candidate: &str) -> Vec<ReverseEntry> {
+    self.dict.look_up_readings(candidate)
+  }
+
+  // ddskk records a kanji purged from the system dictionary
+  // as a candidate in the form of (skk-ignore-dic-word "kanji")
+  fn to_ignore_marker(kanji: &str) -> String {
+    let kanji = kanji.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("(skk-ignore-dic-word \"{}\")", kanji)
+  }
+
+  // returns the kanjis listed in the marker or None if not a marker
+  fn parse_ignore_marker(s: &str) -> Option<Vec<String>> {
+    let args = s.strip_prefix("(skk-ignore-dic-word")?.strip_suffix(')')?;
+
+    let mut kanjis = vec![];
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+      if c != '"' {
+        continue;
+      }
+      let mut kanji = String::new();
+      while let Some(c) = chars.next() {
+        match c {
+          '"' => break,
+          '\\' => if let Some(c) = chars.next() {
+            kanji.push(c);
+          },
+          c => kanji.push(c),
+        }
+      }
+      kanjis.push(kanji);
+    }
+    Some(kanjis)
+  }
+
+  // returns the user kanjis followed by the system kanjis that are not hidden by the user.
+  // user kanjis learned w/ the okuri kana come first. ignore markers and duplicates are excluded
+  pub fn merge(
+    &self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+    system_kanjis: Option<&Vec<Candidate>>,
+  ) -> Vec<Candidate> {
+    let mut kanjis: Vec<Candidate> = vec![];
+    let mut ignored_kanjis = vec![];
+
+    if let Some(user_kanjis) = self.dict.look_up_okuri_strict(readings, acc_kana, okuri) {
+      for kanji in user_kanjis {
+        match UserDict::parse_ignore_marker(&kanji.kanji) {
+          Some(mut xs) => ignored_kanjis.append(&mut xs),
+          None => kanjis.push(kanji),
+        }
+      }
+    }
+    if let Some(system_kanjis) = system_kanjis {
+      for kanji in system_kanjis {
+        if !ignored_kanjis.contains(&kanji.kanji) {
+          Candidate::add_unique(&mut kanjis, kanji);
+        }
+      }
+    }
+    kanjis
+  }
+
+  // words that cannot be written to the file are rejected
+  // not to make the whole dictionary unsavable
+  fn check_encodable(&self, kanji: &str) -> Result<()> {
+    if self.has_encoding && self.dict.encoding().encode_strict(kanji).is_none() {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("Word cannot be written in the encoding of the user dictionary: '{}'", kanji),
+      ));
+    }
+    Ok(())
+  }
+
+  // marks the entry of the readings as the most recently used one
+  fn touch(&mut self, readings: &[char], acc_kana: &Option<char>) {
+    let key: String = readings.iter().chain(acc_kana).collect();
+    self.stamps.insert(key, self.next_stamp);
+    self.next_stamp += 1;
+  }
+
+  // moves the committed kanji to the front of the reading/accompanying kana pair.
+  // the kanji is recorded in the okuri block
+  // of the okuri kana too unless it is empty
+  pub fn commit(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+    kanji: &str,
+  ) -> Result<()> {
+    self.check_encodable(kanji)?;
+    self.dict.remove(readings, acc_kana, &UserDict::to_ignore_marker(kanji));
+    self.dict.move_to_front_okuri(readings, acc_kana, okuri, kanji);
+    self.touch(readings, acc_kana);
+    self.dirty = true;
+    Ok(())
+  }
+
+  // removes the kanji from the user dictionary. if the kanji comes from the system dictionary,
+  // an ignore marker is recorded instead so that the kanji is hidden from the merged result
+  pub fn purge(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    kanji: &str,
+    in_system_dict: bool,
+  ) -> Result<()> {
+    let removed = self.dict.remove(readings, acc_kana, kanji);
+
+    if in_system_dict {
+      let marker = UserDict::to_ignore_marker(kanji);
+      let kanjis = self.dict.look_up(&readings.to_vec(), acc_kana);
+      if !kanjis.is_some_and(|xs| xs.contains(&marker.as_str())) {
+        self.dict.add(readings, acc_kana, &marker);
+      }
+    } else if !removed {
+      return Err(Error::new(
+        ErrorKind::NotFound,
+        format!("Kanji not found: '{}'", kanji),
+      ));
+    }
+    self.dirty = true;
+    Ok(())
+  }
+
+  // adds a new word for the reading/accompanying kana pair in front of the existing kanjis.
+  // the okuri kana is handled as in commit
+  pub fn register(
+    &mut self,
+    readings: &[char],
+    acc_kana: &Option<char>,
+    okuri: &str,
+    kanji: &str,
+  ) -> Result<()> {
+    if readings.is_empty() || kanji.is_empty() {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        "Reading and word must not be empty",
+      ));
+    }
+    // '/' and ';' are quoted when written but line breaks cannot be
+    if kanji.contains(['\n', '\r']) {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("Word contains an unsupported char: '{}'", kanji),
+      ));
+    }
+    self.check_encodable(kanji)?;
+    self.dict.remove(readings, acc_kana, &UserDict::to_ignore_marker(kanji));
+    self.dict.move_to_front_okuri(readings, acc_kana, okuri, kanji);
+    self.touch(readings, acc_kana);
+    self.dirty = true;
+    Ok(())
+  }
+
+  // lines of each section in the order of recency as ddskk writes them
+  fn to_lines(&self) -> Vec<String> {
+    let mut lines = self.dict.to_lines();
+    // the section markers stay in place
+    for section in lines.split_mut(|x| x.starts_with(";;")) {
+      section.sort_by_key(|x| Reverse(self.stamps.get(key_of_line(x)).copied().unwrap_or(0)));
+    }
+    lines
+  }
+
+  // returns the lines encoded in the encoding of the loaded file.
+  // a new file is switched to UTF-8 if the encoding cannot represent a word such as an emoji
+  // since encoding_rs would write it as a numeric character reference.
+  // an existing file keeps its encoding since ddskk may read it w/o looking at the header
+  fn encode_lines(&mut self) -> Result<Vec<u8>> {
+    let lines = self.to_lines();
+    let encoding = self.dict.encoding();
+    let encoded: Option<Vec<Vec<u8>>> = lines.iter().map(|x| encoding.encode_strict(x)).collect();
+    let encoded = match encoded {
+      Some(encoded) => encoded,
+      None if !self.has_encoding => {
+        self.dict.set_encoding(DictEncoding::Utf8);
+        lines.into_iter().map(|x| x.into_bytes()).collect()
+      },
+      None => return Err(Error::new(
+        ErrorKind::InvalidData,
+        "The user dictionary has words that cannot be written in its encoding",
+      )),
+    };
+
+    let mut bytes = vec![];
+    if self.dict.encoding() == DictEncoding::Utf8 {
+      bytes.extend_from_slice(UTF8_HEADER.as_bytes());
+      bytes.push(b'\n');
+    }
+    for line in encoded {
+      bytes.extend_from_slice(&line);
+      bytes.push(b'\n');
+    }
+    self.has_encoding = true;
+    Ok(bytes)
+  }
+
+  // the changes made by commit, purge and register are kept in memory until saved
+  pub fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  // marks the changes as not saved when the snapshot failed to be written
+  pub fn mark_dirty(&mut self) {
+    self.dirty = true;
+  }
+
+  // returns the contents to save if the dictionary has changes.
+  // fails if any line was skipped while loading since it would be lost from the file
+  pub fn take_snapshot(&mut self) -> Result<Option<Snapshot>> {
+    if !self.dirty {
+      return Ok(None);
+    }
+    if self.dict.encoding() == DictEncoding::EucJis2004 {
+      return Err(Error::new(
+        ErrorKind::Unsupported,
+        "EUC-JIS-2004 is not supported. Convert the file to UTF-8 to save the changes",
+      ));
+    }
+    if let Some(warning) = self.warnings.first() {
+      return Err(Error::new(
+        ErrorKind::InvalidData,
+        format!(
+          "{} lines of the user dictionary could not be loaded. Fix line {} ({}) to save the changes",
+          self.warnings.len(),
+          warning.line_num,
+          warning.reason,
+        ),
+      ));
+    }
+    let bytes = self.encode_lines()?;
+    self.dirty = false;
+    Ok(Some(Snapshot {
+      path: self.path.clone(),
+      bytes,
+    }))
+  }
+
+  // writes the changes if any
+  pub fn save(&mut self) -> Result<()> {
+    match self.take_snapshot()? {
+      Some(snapshot) => snapshot.write().inspect_err(|_| self.mark_dirty()),
+      None => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("minskk-{}-{}", std::process::id(), name))
+  }
+
+  // the file is written in EUC-JP unless the words require UTF-8
+  fn read_lines(path: &PathBuf) -> Vec<String> {
+    DictEncoding::EucJp.decode(&fs::read(path).unwrap()).lines().map(|x| x.to_string()).collect()
+  }
+
+  #[test]
+  pub fn test_commit_moves_kanji_to_front_and_persists() {
+    let path = temp_path("user-dict-commit");
+    let _ = fs::remove_file(&path);
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+    let readings = vec!['か', 'ん'];
+    assert!(user_dict.look_up(&readings, &None).is_none());
+
+    user_dict.commit(&readings, &None, "", "缶").unwrap();
+    user_dict.commit(&readings, &None, "", "管").unwrap();
+    user_dict.commit(&readings, &None, "", "缶").unwrap();
+    user_dict.commit(&['か'], &Some('k'), "", "書").unwrap();
+    assert_eq!(user_dict.look_up(&readings, &None).unwrap(), vec!["缶", "管"]);
+
+    // should be written only when saved
+    assert!(user_dict.is_dirty());
+    assert!(!path.exists());
+    user_dict.save().unwrap();
+    assert!(!user_dict.is_dirty());
+
+    // should be restored from the file
+    let user_dict = UserDict::load(&path).unwrap();
+    assert_eq!(user_dict.look_up(&readings, &None).unwrap(), vec!["缶", "管"]);
+    assert_eq!(user_dict.look_up(&vec!['か'], &Some('k')).unwrap(), vec!["書"]);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_register_word() {
+    let path = temp_path("user-dict-register");
+    let _ = fs::remove_file(&path);
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+
+    // okuri-nasi and okuri-ari words should be visible right away
+    user_dict.register(&['み', 'ん'], &None, "", "眠").unwrap();
+    user_dict.register(&['ね', 'む'], &Some('r'), "", "睡").unwrap();
+    user_dict.register(&['ね', 'む'], &Some('r'), "", "眠").unwrap();
+    assert_eq!(user_dict.look_up(&vec!['み', 'ん'], &None).unwrap(), vec!["眠"]);
+    assert_eq!(user_dict.look_up(&vec!['ね', 'む'], &Some('r')).unwrap(), vec!["眠", "睡"]);
+    assert!(user_dict.look_up(&vec!['ね', 'む'], &None).is_none());
+
+    // malformed words should be rejected
+    assert!(user_dict.register(&['み', 'ん'], &None, "", "").is_err());
+    assert!(user_dict.register(&[], &None, "", "眠").is_err());
+    assert!(user_dict.register(&['み', 'ん'], &None, "", "a\nb").is_err());
+
+    // words w/ '/' should be quoted in the file
+    let readings: Vec<char> = "どすぶい".chars().collect();
+    user_dict.register(&readings, &None, "", "DOS/V").unwrap();
+    user_dict.save().unwrap();
+
+    let user_dict = UserDict::load(&path).unwrap();
+    assert_eq!(user_dict.look_up(&vec!['ね', 'む'], &Some('r')).unwrap(), vec!["眠", "睡"]);
+    assert_eq!(user_dict.look_up(&readings, &None).unwrap(), vec!["(concat \"DOS\\057V\")"]);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_purge() {
+    let path = temp_path("user-dict-purge");
+    let _ = fs::remove_file(&path);
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+    let readings = vec!['か', 'ん'];
+    let system_kanjis = vec![Candidate::new("缶"), Candidate::new("管"), Candidate::new("巻")];
+
+    user_dict.register(&readings, &None, "", "完").unwrap();
+    user_dict.commit(&readings, &None, "", "管").unwrap();
+    assert_eq!(
+      user_dict.merge(&readings, &None, "", Some(&system_kanjis)),
+      vec!["管", "完", "缶", "巻"],
+    );
+
+    // user kanjis should be removed and system kanjis should be hidden
+    user_dict.purge(&readings, &None, "完", false).unwrap();
+    user_dict.purge(&readings, &None, "管", true).unwrap();
+    user_dict.purge(&readings, &None, "巻", true).unwrap();
+    assert!(user_dict.purge(&readings, &None, "完", false).is_err());
+    assert_eq!(user_dict.merge(&readings, &None, "", Some(&system_kanjis)), vec!["缶"]);
+    // the reading only has the ignore markers left
+    assert!(user_dict.complete(&['か'], 10).is_empty());
+    user_dict.save().unwrap();
+
+    // hidden kanjis should stay hidden after reloading
+    let mut user_dict = UserDict::load(&path).unwrap();
+    assert_eq!(user_dict.merge(&readings, &None, "", Some(&system_kanjis)), vec!["缶"]);
+
+    // committing a hidden kanji again should bring it back
+    user_dict.commit(&readings, &None, "", "巻").unwrap();
+    assert_eq!(user_dict.merge(&readings, &None, "", Some(&system_kanjis)), vec!["巻", "缶"]);
+    assert_eq!(user_dict.complete(&['か'], 10), vec!["かん"]);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_okuri() {
+    let path = temp_path("user-dict-okuri");
+    let _ = fs::remove_file(&path);
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+    let readings = vec!['か', 'え'];
+    let system_kanjis = vec![Candidate::new("帰"), Candidate::new("返"), Candidate::new("換")];
+
+    user_dict.commit(&readings, &Some('r'), "る", "返").unwrap();
+    user_dict.commit(&readings, &Some('r'), "れ", "換").unwrap();
+
+    // kanjis committed w/ the okuri kana should come first
+    assert_eq!(
+      user_dict.merge(&readings, &Some('r'), "る", Some(&system_kanjis)),
+      vec!["返", "換", "帰"],
+    );
+    assert_eq!(
+      user_dict.merge(&readings, &Some('r'), "れ", Some(&system_kanjis)),
+      vec!["換", "返", "帰"],
+    );
+    assert_eq!(
+      user_dict.merge(&readings, &Some('r'), "", Some(&system_kanjis)),
+      vec!["換", "返", "帰"],
+    );
+    user_dict.save().unwrap();
+
+    // okuri blocks should be persisted
+    let user_dict = UserDict::load(&path).unwrap();
+    assert_eq!(
+      user_dict.merge(&readings, &Some('r'), "る", Some(&system_kanjis)),
+      vec!["返", "換", "帰"],
+    );
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_save_unencodable_word() {
+    let path = temp_path("user-dict-utf8");
+    let _ = fs::remove_file(&path);
+
+    // EUC-JP cannot represent the emoji but a new file can be written in UTF-8
+    let mut user_dict = UserDict::load(&path).unwrap();
+    user_dict.register(&['か', 'ん'], &None, "", "缶").unwrap();
+    user_dict.register(&['え', 'み'], &None, "", "😀").unwrap();
+    user_dict.save().unwrap();
+    assert!(fs::read_to_string(&path).unwrap().starts_with(UTF8_HEADER));
+
+    let user_dict = UserDict::load(&path).unwrap();
+    assert_eq!(user_dict.dict.encoding(), DictEncoding::Utf8);
+    assert_eq!(user_dict.look_up(&vec!['え', 'み'], &None).unwrap(), vec!["😀"]);
+    assert_eq!(user_dict.look_up(&vec!['か', 'ん'], &None).unwrap(), vec!["缶"]);
+    fs::remove_file(&path).unwrap();
+
+    // an existing EUC-JP file should keep its encoding
+    let mut user_dict = UserDict::load(&path).unwrap();
+    user_dict.register(&['か', 'ん'], &None, "", "缶").unwrap();
+    user_dict.save().unwrap();
+    assert!(user_dict.register(&['え', 'み'], &None, "", "😀").is_err());
+    assert!(user_dict.commit(&['え', 'み'], &None, "", "😀").is_err());
+    assert!(!user_dict.is_dirty());
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+    assert!(user_dict.register(&['え', 'み'], &None, "", "😀").is_err());
+    user_dict.register(&['あ', 'い'], &None, "", "愛").unwrap();
+    user_dict.save().unwrap();
+    assert_eq!(read_lines(&path), vec![";; okuri-ari entries.", ";; okuri-nasi entries.", "あい /愛/", "かん /缶/"]);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_refuse_to_save_euc_jis_2004() {
+    let path = temp_path("user-dict-euc-jis-2004");
+    let header = b";; -*- coding: euc-jis-2004 -*-\n;; okuri-nasi entries.\n";
+    // 𠀋 is in plane 2 of JIS X 0213
+    let bytes = [header.as_slice(), b"\xa4\xa2 /\x8f\xa1\xa2/\n"].concat();
+    fs::write(&path, &bytes).unwrap();
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+    assert_eq!(user_dict.warnings().len(), 1);
+    assert_eq!(user_dict.warnings()[0].line_num, 3);
+
+    // the line that could not be decoded should not be lost
+    user_dict.register(&['か', 'ん'], &None, "", "缶").unwrap();
+    assert!(user_dict.save().is_err());
+    assert!(user_dict.is_dirty());
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_refuse_to_save_w_malformed_lines() {
+    let path = temp_path("user-dict-malformed");
+    let bytes = ";; okuri-nasi entries.\nかん /缶/\nmalformed\n".as_bytes();
+    fs::write(&path, bytes).unwrap();
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+    assert_eq!(user_dict.warnings().len(), 1);
+
+    // the malformed line should not be lost
+    user_dict.register(&['か', 'ん'], &None, "", "管").unwrap();
+    assert!(user_dict.save().is_err());
+    assert!(user_dict.is_dirty());
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_save_in_recency_order() {
+    let path = temp_path("user-dict-recency");
+    let _ = fs::remove_file(&path);
+
+    let mut user_dict = UserDict::load(&path).unwrap();
+    user_dict.register(&['か', 'ん'], &None, "", "缶").unwrap();
+    user_dict.register(&['あ', 'い'], &None, "", "愛").unwrap();
+    user_dict.register(&['か', 'え'], &Some('r'), "", "帰").unwrap();
+    user_dict.register(&['あ', 'つ'], &Some('k'), "", "厚").unwrap();
+    user_dict.commit(&['か', 'ん'], &None, "", "管").unwrap();
+    user_dict.save().unwrap();
+
+    let mut expected = vec![
+      ";; okuri-ari entries.",
+      "あつk /厚/",
+      "かえr /帰/",
+      ";; okuri-nasi entries.",
+      "かん /管/缶/",
+      "あい /愛/",
+    ];
+    assert_eq!(read_lines(&path), expected);
+
+    // the order of the file should be kept by the dictionary loaded from it
+    let mut user_dict = UserDict::load(&path).unwrap();
+    user_dict.register(&['い'], &None, "", "胃").unwrap();
+    user_dict.save().unwrap();
+    expected.insert(4, "い /胃/");
+    assert_eq!(read_lines(&path), expected);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  pub fn test_parse_ignore_marker() {
+    let marker = UserDict::to_ignore_marker("a\"b");
+    assert_eq!(UserDict::parse_ignore_marker(&marker).unwrap(), vec!["a\"b"]);
+    assert_eq!(
+      UserDict::parse_ignore_marker("(skk-ignore-dic-word \"缶\" \"管\")").unwrap(),
+      vec!["缶", "管"],
+    );
+    assert!(UserDict::parse_ignore_marker("缶").is_none());
+  }
+}
@@ -0,0 +1,167 @@
+// map backed by a vector sorted by the key.
+// the nodes of a dictionary have a few entries at most and most of them have none,
+// for which HashMap takes several times more memory than the entries themselves
+
+use serde::{Serialize, Deserialize};
+use std::{
+  borrow::Borrow,
+  mem,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VecMap<K, V>(Vec<(K, V)>);
+
+impl<K: Ord, V> VecMap<K, V> {
+  pub fn new() -> Self {
+    VecMap(vec![])
+  }
+
+  fn search<Q>(&self, key: &Q) -> Result<usize, usize>
+  where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+  {
+    self.0.binary_search_by(|(k, _)| k.borrow().cmp(key))
+  }
+
+  pub fn get<Q>(&self, key: &Q) -> Option<&V>
+  where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+  {
+    let i = self.search(key).ok()?;
+    Some(&self.0[i].1)
+  }
+
+  pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+  where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+  {
+    let i = self.search(key).ok()?;
+    Some(&mut self.0[i].1)
+  }
+
+  // same as HashMap::entry(key).or_default()
+  pub fn get_or_default(&mut self, key: K) -> &mut V
+  where
+    V: Default,
+  {
+    let i = match self.search(&key) {
+      Ok(i) => i,
+      Err(i) => {
+        self.0.insert(i, (key, V::default()));
+        i
+      },
+    };
+    &mut self.0[i].1
+  }
+
+  pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+  where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+  {
+    let i = self.search(key).ok()?;
+    Some(self.0.remove(i).1)
+  }
+
+  pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+    self.0.retain_mut(|(k, v)| f(k, v));
+  }
+
+  // in the order of the keys
+  pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+    self.0.iter().map(|(k, v)| (k, v))
+  }
+
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+    self.0.iter_mut().map(|(k, v)| (&*k, v))
+  }
+
+  pub fn values(&self) -> impl Iterator<Item = &V> {
+    self.0.iter().map(|(_, v)| v)
+  }
+
+  pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+    self.0.iter_mut().map(|(_, v)| v)
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  // moves the entries of the other map. the keys of the maps should not overlap
+  pub fn append(&mut self, other: Self) {
+    self.0.extend(other.0);
+    self.0.sort_by(|a, b| a.0.cmp(&b.0));
+  }
+
+  // drops the capacity left by the insertions
+  pub fn shrink_to_fit(&mut self) {
+    self.0.shrink_to_fit();
+  }
+
+  // bytes allocated for the entries excluding the ones they own
+  pub fn heap_size(&self) -> usize {
+    self.0.capacity() * mem::size_of::<(K, V)>()
+  }
+}
+
+impl<K: Ord, V> Default for VecMap<K, V> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a VecMap<K, V> {
+  type Item = (&'a K, &'a V);
+  type IntoIter = std::iter::Map<std::slice::Iter<'a, (K, V)>, fn(&'a (K, V)) -> (&'a K, &'a V)>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.0.iter().map(|(k, v)| (k, v))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn test_sorted_by_key() {
+    let mut map: VecMap<char, Vec<u32>> = VecMap::new();
+    assert_eq!(map.heap_size(), 0);
+
+    for (k, v) in [('c', 3), ('a', 1), ('b', 2), ('a', 10)] {
+      map.get_or_default(k).push(v);
+    }
+    let keys: Vec<char> = map.iter().map(|(k, _)| *k).collect();
+    assert_eq!(keys, vec!['a', 'b', 'c']);
+    assert_eq!(map.get(&'a'), Some(&vec![1, 10]));
+    assert_eq!(map.get(&'d'), None);
+
+    map.get_mut(&'b').unwrap().clear();
+    map.retain(|_, v| !v.is_empty());
+    assert_eq!(map.remove(&'c'), Some(vec![3]));
+    assert_eq!(map.len(), 1);
+
+    map.shrink_to_fit();
+    assert_eq!(map.heap_size(), mem::size_of::<(char, Vec<u32>)>());
+
+    let mut other: VecMap<char, Vec<u32>> = VecMap::new();
+    other.get_or_default('0').push(0);
+    other.get_or_default('z').push(26);
+    map.append(other);
+    let keys: Vec<char> = map.iter().map(|(k, _)| *k).collect();
+    assert_eq!(keys, vec!['0', 'a', 'z']);
+
+    // okuri blocks are keyed by the accompanying kana and the okuri kana
+    let mut map: VecMap<(char, String), u32> = VecMap::new();
+    *map.get_or_default(('s', "し".to_string())) += 1;
+    assert_eq!(map.get(&('s', "し".to_string())), Some(&1));
+  }
+}